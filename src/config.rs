@@ -0,0 +1,801 @@
+use crate::error::{ConfigError, InvalidTempRangeError};
+use crate::fan::FanCurve;
+
+// Min speed as percentage to run the fan.
+pub const FAN_SPEED_MIN: u8 = 30;
+// Max speed as percentage to run the fan.
+pub const FAN_SPEED_MAX: u8 = 100;
+
+// Minimum temperature from start to raise fan speed.
+pub const MIN_TEMP: u8 = 70;
+// Maximun temperature that starts raising fan speed even if temperature is not raising.
+pub const MAX_TEMP: u8 = 85;
+// Temperature that sets max fan speed right away, ignoring every delay and limit.
+pub const CRITICAL_TEMP: u8 = 90;
+
+// Wait time between loops in ms.
+pub const REFRESH_RATE: u64 = 250;
+
+// Wait this many milliseconds to change speed when temperature is raising.
+pub const REACTION_TIME_MS_RAISE: u64 = 1000;
+// Wait this many milliseconds to change speed when temperature is lowering or staying same.
+pub const REACTION_TIME_MS_LOWER: u64 = 2000;
+
+// Increment as percentage to raise fan speed.
+pub const FAN_RAISE_INCREMENT: u8 = 5;
+// Increment as percentage to lower fan speed.
+pub const FAN_LOWER_INCREMENT: u8 = 1;
+
+// How many degrees temperature must drop under the one that last raised fan speed before lowering.
+pub const HYSTERESIS: u8 = 0;
+
+// Temperature that PID control mode targets.
+pub const PID_SETPOINT: u8 = 70;
+// Proportional gain of PID control mode.
+pub const PID_KP: f32 = 5.0;
+// Integral gain of PID control mode.
+pub const PID_KI: f32 = 0.1;
+// Derivative gain of PID control mode.
+pub const PID_KD: f32 = 1.0;
+
+// How many degrees over passive threshold temperature must rise to leave passive mode.
+pub const PASSIVE_HYSTERESIS: u8 = 3;
+
+// Wait this many milliseconds to lower fan speed when system is idle.
+pub const IDLE_REACTION_TIME_MS_LOWER: u64 = 6000;
+// Max speed as percentage to run the fan when system is idle.
+pub const IDLE_FAN_SPEED_MAX: u8 = 60;
+// Log kept fan speed at most once per this many milliseconds while it stays the same.
+pub const KEEPING_LOG_MS: u64 = 60000;
+// Wait this many milliseconds to raise fan speed in turbo.
+pub const TURBO_REACTION_TIME_MS_RAISE: u64 = 250;
+
+// How many milliseconds over refresh rate sleep must take to be considered suspend.
+pub const RESUME_GAP_MS: u64 = 5000;
+
+// How many milliseconds to run fans at max speed in spin test.
+pub const SPIN_TEST_MS: u64 = 2000;
+
+// How many percents fan speed is raised between capture steps.
+pub const CAPTURE_STEP: u8 = 10;
+// How many milliseconds capture dwells at each speed before recording.
+pub const CAPTURE_DWELL_MS: u64 = 10000;
+// How many milliseconds capture sleeps between checks for interruption while dwelling.
+pub const CAPTURE_POLL_MS: u64 = 100;
+
+// How many milliseconds stopped fan is kicked at higher speed before dropping to wanted speed.
+pub const SPINUP_MS: u64 = 1000;
+
+// Fan speed changing to opposite direction within this many milliseconds counts as oscillation
+// in adaptive mode, lengthening reaction times.
+pub const ADAPTIVE_REVERSAL_MS: u64 = 10000;
+// Fan speed raising this many times in a row counts as lag in adaptive mode, shortening reaction times.
+pub const ADAPTIVE_LAG_RAISES: u32 = 3;
+// How many percents of configured reaction times adaptive mode changes them by at a time.
+pub const ADAPTIVE_SCALE_STEP: u32 = 25;
+// Limits of reaction times scaled by adaptive mode, as percentage of configured ones.
+pub const ADAPTIVE_SCALE_MIN: u32 = 50;
+pub const ADAPTIVE_SCALE_MAX: u32 = 400;
+
+// How many milliseconds fans are ramped down over on shutdown before restoring automatic control.
+pub const SHUTDOWN_RAMP_MS: u64 = 1000;
+// How many speed writes shutdown ramp is split into.
+pub const SHUTDOWN_RAMP_STEPS: u16 = 10;
+
+// Temperatures that preview plots steady state fan speed at.
+pub const PREVIEW_TEMP_MIN: u8 = 30;
+pub const PREVIEW_TEMP_MAX: u8 = 100;
+// How many milliseconds preview runs controller at each temperature to reach steady state.
+pub const PREVIEW_SETTLE_MS: u64 = 600000;
+
+// Highest temperature that diagnostics accept as plausible reading.
+pub const DIAGNOSE_TEMP_MAX: u8 = 110;
+// Speed that diagnostics write and read back.
+pub const DIAGNOSE_SPEED: u8 = 50;
+
+// Bits of speed register that hold fan speed by default, all of them.
+pub const SPEED_MASK: u8 = 0xFF;
+// How many bits fan speed is shifted left in speed register by default.
+pub const SPEED_SHIFT: u8 = 0;
+
+// Default path for configuration file.
+pub const CONFIG_PATH_DEFAULT: &str = "/etc/clevo-fan.toml";
+
+// Algorithm used to decide fan speed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+#[cfg_attr(feature = "bin", derive(clap::ValueEnum))]
+#[serde(rename_all = "lowercase")]
+pub enum Mode {
+    // Raise and lower fan speed by increments.
+    Increment,
+    // Set fan speed from PID controller targeting setpoint temperature.
+    Pid,
+}
+
+// Named preset of temperature limits, increments and reaction times.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "bin", derive(clap::ValueEnum))]
+pub enum Profile {
+    // Keep fan slow, raising late and slowly.
+    Quiet,
+    // Default values.
+    Balanced,
+    // Raise fan early and aggressively, lowering slowly.
+    Performance,
+}
+impl std::str::FromStr for Profile {
+    type Err = String;
+
+    // Parse profile from its lowercase name, ignoring case.
+    fn from_str(name: &str) -> Result<Profile, String> {
+        return match name.to_lowercase().as_str() {
+            "quiet" => Ok(Profile::Quiet),
+            "balanced" => Ok(Profile::Balanced),
+            "performance" => Ok(Profile::Performance),
+            _ => Err(format!(
+                "unknown profile {}, expected quiet, balanced or performance",
+                name
+            )),
+        };
+    }
+}
+
+// Configuration filled with values of given profile.
+pub fn profile_defaults(profile: Profile) -> Config {
+    return match profile {
+        Profile::Quiet => Config {
+            min_temp: 75,
+            max_temp: 90,
+            reaction_time_ms_raise: 3000,
+            reaction_time_ms_lower: 5000,
+            fan_raise_increment: 2,
+            fan_lower_increment: 1,
+            ..Config::default()
+        },
+        Profile::Balanced => Config::default(),
+        Profile::Performance => Config {
+            min_temp: 60,
+            max_temp: 80,
+            reaction_time_ms_raise: 250,
+            reaction_time_ms_lower: 4000,
+            fan_raise_increment: 10,
+            fan_lower_increment: 1,
+            ..Config::default()
+        },
+    };
+}
+
+// Speed limits of single fan, missing limits fall back to global ones.
+#[derive(Debug, Clone, serde::Deserialize, serde::Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct FanLimits {
+    // Id of fan that limits apply to.
+    pub id: u8,
+    #[serde(default)]
+    pub min: Option<u8>,
+    #[serde(default)]
+    pub max: Option<u8>,
+}
+
+// Fan control configuration, missing fields fall back to constants.
+#[derive(Debug, Clone, serde::Deserialize, serde::Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub fan_speed_min: u8,
+    pub fan_speed_max: u8,
+    pub min_temp: u8,
+    pub max_temp: u8,
+    // Set fan to max speed right away at or over this temperature, ignoring every delay and limit.
+    // Unset uses default critical temperature, raised to maximum temperature when that is higher.
+    pub critical_temp: Option<u8>,
+    pub refresh_rate: u64,
+    pub reaction_time_ms_raise: u64,
+    pub reaction_time_ms_lower: u64,
+    // Wait this many milliseconds to raise fan speed while temperature is over maximum,
+    // zero raises right away and unset uses raise reaction time.
+    pub reaction_time_ms_over_max: Option<u64>,
+    pub fan_raise_increment: u8,
+    pub fan_lower_increment: u8,
+    // Algorithm used to decide fan speed.
+    pub mode: Mode,
+    // Fan curve that gives target speed from temperature, replaces increment logic when set.
+    pub curve: Option<FanCurve>,
+    // How many degrees temperature must drop under the one that last raised fan speed before lowering.
+    pub hysteresis: u8,
+    // Turn fan fully off, bypassing minimum speed, when temperature is under this.
+    pub passive_below: Option<u8>,
+    // How many degrees over passive threshold temperature must rise to leave passive mode.
+    pub passive_hysteresis: u8,
+    // Semi-passive control: stop fan under stop temperature, keep it at minimum speed between stop
+    // and start temperatures and resume normal control once temperature rises over start temperature.
+    pub stop_temp: Option<u8>,
+    pub start_temp: Option<u8>,
+    // Keep fan running at least this many milliseconds after leaving passive mode before stopping it again.
+    pub min_on_ms: u64,
+    // Keep fan stopped at least this many milliseconds after entering passive mode before starting it again,
+    // unless temperature goes over maximum.
+    pub min_off_ms: u64,
+    // Embedded controller command byte followed by data bytes sent instead of speed 0 to stop fan.
+    pub fan_off_sequence: Option<Vec<u8>>,
+    // Embedded controller command byte followed by data bytes sent before first speed after stopping fan.
+    pub fan_resume_sequence: Option<Vec<u8>>,
+    // Bits of speed register that hold fan speed, other bits are kept as they are.
+    pub speed_mask: u8,
+    // How many bits fan speed is shifted left in speed register.
+    pub speed_shift: u8,
+    // How many percents applied fan speed can change per write, no limit when unset or zero.
+    pub max_step: Option<u8>,
+    // Only apply new fan speed when it differs from current one by more than this many percents.
+    pub deadband: u8,
+    // Raise fan speed without waiting for reaction time when temperature rises faster
+    // than this many degrees per second.
+    pub fast_rise: Option<f32>,
+    // Keep cooling this many milliseconds after fan speed would first be lowered.
+    pub cooldown_ms: u64,
+    // Keep current fan speed instead of lowering it while temperature over minimum stays the same.
+    pub hold_on_steady: bool,
+    // Experimental: lengthen reaction times when fan speed oscillates and shorten them when it lags.
+    pub adaptive: bool,
+    // System is idle when 1 minute load average is under this, idle detection is off when unset.
+    pub idle_load: Option<f32>,
+    // Wait this many milliseconds to lower fan speed when system is idle.
+    pub idle_reaction_time_ms_lower: u64,
+    // Max speed as percentage when system is idle and temperature is not over maximum.
+    pub idle_fan_speed_max: u8,
+    // Run fan at least this many percents over minimum speed at full CPU utilization,
+    // proportionally less at lower utilization, before temperature rises. Off when zero.
+    pub cpu_weight: f32,
+    // Speed limits of fans, overriding global minimum and maximum speeds.
+    pub fans: Vec<FanLimits>,
+    // Temperature that PID control mode targets.
+    pub pid_setpoint: u8,
+    // Proportional gain of PID control mode.
+    pub pid_kp: f32,
+    // Integral gain of PID control mode.
+    pub pid_ki: f32,
+    // Derivative gain of PID control mode.
+    pub pid_kd: f32,
+}
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            fan_speed_min: FAN_SPEED_MIN,
+            fan_speed_max: FAN_SPEED_MAX,
+            min_temp: MIN_TEMP,
+            max_temp: MAX_TEMP,
+            critical_temp: None,
+            refresh_rate: REFRESH_RATE,
+            reaction_time_ms_raise: REACTION_TIME_MS_RAISE,
+            reaction_time_ms_lower: REACTION_TIME_MS_LOWER,
+            reaction_time_ms_over_max: None,
+            fan_raise_increment: FAN_RAISE_INCREMENT,
+            fan_lower_increment: FAN_LOWER_INCREMENT,
+            mode: Mode::Increment,
+            curve: None,
+            hysteresis: HYSTERESIS,
+            passive_below: None,
+            passive_hysteresis: PASSIVE_HYSTERESIS,
+            stop_temp: None,
+            start_temp: None,
+            min_on_ms: 0,
+            min_off_ms: 0,
+            fan_off_sequence: None,
+            fan_resume_sequence: None,
+            speed_mask: SPEED_MASK,
+            speed_shift: SPEED_SHIFT,
+            max_step: None,
+            deadband: 0,
+            fast_rise: None,
+            cooldown_ms: 0,
+            hold_on_steady: false,
+            adaptive: false,
+            idle_load: None,
+            idle_reaction_time_ms_lower: IDLE_REACTION_TIME_MS_LOWER,
+            idle_fan_speed_max: IDLE_FAN_SPEED_MAX,
+            cpu_weight: 0.0,
+            fans: Vec::new(),
+            pid_setpoint: PID_SETPOINT,
+            pid_kp: PID_KP,
+            pid_ki: PID_KI,
+            pid_kd: PID_KD,
+        }
+    }
+}
+impl Config {
+    // Load configuration from given TOML file.
+    pub fn load(path: &std::path::Path) -> Result<Config, ConfigError> {
+        let content: String = match std::fs::read_to_string(path) {
+            Ok(content) => content,
+            Err(error) => return Err(ConfigError::ReadError(path.to_path_buf(), error)),
+        };
+        return match toml::from_str::<Config>(&content) {
+            Ok(config) => Ok(config),
+            Err(error) => Err(ConfigError::ParseError(path.to_path_buf(), error)),
+        };
+    }
+
+    // Replace temperature limits, increments and reaction times with ones from given profile.
+    pub fn apply_profile(&mut self, profile: Profile) {
+        let preset: Config = profile_defaults(profile);
+        self.min_temp = preset.min_temp;
+        self.max_temp = preset.max_temp;
+        self.reaction_time_ms_raise = preset.reaction_time_ms_raise;
+        self.reaction_time_ms_lower = preset.reaction_time_ms_lower;
+        self.fan_raise_increment = preset.fan_raise_increment;
+        self.fan_lower_increment = preset.fan_lower_increment;
+    }
+
+    // Speed limits of given fan if it has them.
+    fn fan_limits(&self, fan_id: u8) -> Option<&FanLimits> {
+        return self.fans.iter().find(|limits| limits.id == fan_id);
+    }
+
+    // Minimum speed of given fan.
+    pub fn fan_speed_min_for(&self, fan_id: u8) -> u8 {
+        return match self.fan_limits(fan_id).and_then(|limits| limits.min) {
+            Some(min) => min,
+            None => self.fan_speed_min,
+        };
+    }
+
+    // Maximum speed of given fan.
+    pub fn fan_speed_max_for(&self, fan_id: u8) -> u8 {
+        return match self.fan_limits(fan_id).and_then(|limits| limits.max) {
+            Some(max) => max,
+            None => self.fan_speed_max,
+        };
+    }
+
+    // Configuration for controlling given fan with its own speed limits.
+    pub fn for_fan(&self, fan_id: u8) -> Config {
+        let mut config: Config = self.clone();
+        config.fan_speed_min = self.fan_speed_min_for(fan_id);
+        config.fan_speed_max = self.fan_speed_max_for(fan_id);
+        return config;
+    }
+
+    // Validate that minimum speed of every fan is under its maximum speed.
+    pub fn validate_fan_limits(&self) -> Result<(), ConfigError> {
+        for limits in &self.fans {
+            let min: u8 = self.fan_speed_min_for(limits.id);
+            let max: u8 = self.fan_speed_max_for(limits.id);
+            if max <= min {
+                return Err(ConfigError::InvalidValueError(
+                    format!("fans with id {}", limits.id),
+                    format!(
+                        "minimum speed {} % must be under maximum speed {} %",
+                        min, max
+                    ),
+                ));
+            }
+        }
+        return Ok(());
+    }
+
+    // Validate that fan curve temperatures increase and speeds are within speed limits.
+    pub fn validate_curve(&self) -> Result<(), ConfigError> {
+        let curve: &FanCurve = match &self.curve {
+            Some(curve) => curve,
+            None => return Ok(()),
+        };
+        if curve.points.is_empty() {
+            return Err(ConfigError::InvalidValueError(
+                "curve".to_string(),
+                "must have at least one point".to_string(),
+            ));
+        }
+        for window in curve.points.windows(2) {
+            if window[1].0 <= window[0].0 {
+                return Err(ConfigError::InvalidValueError(
+                    "curve".to_string(),
+                    format!(
+                        "temperatures must increase, {} C is followed by {} C",
+                        window[0].0, window[1].0
+                    ),
+                ));
+            }
+        }
+        for (temp, speed) in &curve.points {
+            if *speed < self.fan_speed_min || self.fan_speed_max < *speed {
+                return Err(ConfigError::InvalidValueError(
+                    "curve".to_string(),
+                    format!(
+                        "speed {} % at {} C must be within minimum speed {} % and maximum speed {} %",
+                        speed, temp, self.fan_speed_min, self.fan_speed_max
+                    ),
+                ));
+            }
+        }
+        return Ok(());
+    }
+
+    // Validate that fan curve temperatures are within minimum and maximum temperature,
+    // so curve does not conflict with them.
+    pub fn validate_curve_temps(&self) -> Result<(), ConfigError> {
+        let curve: &FanCurve = match &self.curve {
+            Some(curve) => curve,
+            None => return Ok(()),
+        };
+        for (temp, speed) in &curve.points {
+            if *temp < self.min_temp || self.max_temp < *temp {
+                return Err(ConfigError::InvalidValueError(
+                    "curve".to_string(),
+                    format!(
+                        "temperature {} C of speed {} % must be within minimum temperature {} C and maximum temperature {} C",
+                        temp, speed, self.min_temp, self.max_temp
+                    ),
+                ));
+            }
+        }
+        return Ok(());
+    }
+
+    // Validate that passive threshold is under temperature that starts raising fan speed.
+    pub fn validate_passive(&self) -> Result<(), ConfigError> {
+        if let Some(passive_below) = self.passive_below {
+            if self.min_temp <= passive_below {
+                return Err(ConfigError::InvalidValueError(
+                    "passive_below".to_string(),
+                    format!(
+                        "{} C must be under minimum temperature {} C",
+                        passive_below, self.min_temp
+                    ),
+                ));
+            }
+        }
+        return Ok(());
+    }
+
+    // Validate that stop and start temperatures are set together, stop one under start one,
+    // and that they do not mix with passive threshold.
+    pub fn validate_semi_passive(&self) -> Result<(), ConfigError> {
+        let (stop_temp, start_temp): (u8, u8) = match (self.stop_temp, self.start_temp) {
+            (None, None) => return Ok(()),
+            (Some(stop_temp), Some(start_temp)) => (stop_temp, start_temp),
+            _ => {
+                return Err(ConfigError::InvalidValueError(
+                    "stop_temp".to_string(),
+                    "stop_temp and start_temp must be set together".to_string(),
+                ))
+            }
+        };
+        if start_temp <= stop_temp {
+            return Err(ConfigError::InvalidValueError(
+                "stop_temp".to_string(),
+                format!(
+                    "{} C must be under start temperature {} C",
+                    stop_temp, start_temp
+                ),
+            ));
+        }
+        if self.passive_below.is_some() {
+            return Err(ConfigError::InvalidValueError(
+                "stop_temp".to_string(),
+                "can not be used together with passive_below".to_string(),
+            ));
+        }
+        return Ok(());
+    }
+
+    // Validate that critical temperature is not under maximum temperature.
+    // Only explicitly set one is validated, default follows maximum temperature.
+    pub fn validate_critical_temp(&self) -> Result<(), ConfigError> {
+        if let Some(critical_temp) = self.critical_temp {
+            if critical_temp < self.max_temp {
+                return Err(ConfigError::InvalidValueError(
+                    "critical_temp".to_string(),
+                    format!(
+                        "{} C must be at least maximum temperature {} C",
+                        critical_temp, self.max_temp
+                    ),
+                ));
+            }
+        }
+        return Ok(());
+    }
+
+    // Temperature that sets max fan speed right away.
+    pub fn critical_temp(&self) -> u8 {
+        return self
+            .critical_temp
+            .unwrap_or(std::cmp::max(CRITICAL_TEMP, self.max_temp));
+    }
+
+    // Validate that fan off and resume sequences have at least command byte.
+    pub fn validate_sequences(&self) -> Result<(), ConfigError> {
+        for (field, sequence) in [
+            ("fan_off_sequence", &self.fan_off_sequence),
+            ("fan_resume_sequence", &self.fan_resume_sequence),
+        ] {
+            if sequence
+                .as_ref()
+                .is_some_and(|sequence| sequence.is_empty())
+            {
+                return Err(ConfigError::InvalidValueError(
+                    field.to_string(),
+                    "must have at least command byte".to_string(),
+                ));
+            }
+        }
+        return Ok(());
+    }
+
+    // Validate that speed mask is one contiguous run of bits starting at speed shift.
+    pub fn validate_speed_mask(&self) -> Result<(), ConfigError> {
+        let field: u8 = self
+            .speed_mask
+            .checked_shr(self.speed_shift as u32)
+            .unwrap_or(0);
+        if field == 0
+            || field.checked_shl(self.speed_shift as u32).unwrap_or(0) != self.speed_mask
+            || field & field.wrapping_add(1) != 0
+        {
+            return Err(ConfigError::InvalidValueError(
+                "speed_mask".to_string(),
+                format!(
+                    "{:#04x} must be contiguous bits starting at shift {}",
+                    self.speed_mask, self.speed_shift
+                ),
+            ));
+        }
+        return Ok(());
+    }
+
+    // Validate whole configuration, collecting every problem instead of stopping at first one.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let mut errors: Vec<ConfigError> = Vec::new();
+        if let Err(error) = self.validate_refresh_rate() {
+            errors.push(error);
+        }
+        if let Err(error) = self.validate_temp_range() {
+            errors.push(ConfigError::InvalidValueError(
+                "min_temp".to_string(),
+                error.to_string(),
+            ));
+        }
+        for result in [
+            self.validate_passive(),
+            self.validate_semi_passive(),
+            self.validate_critical_temp(),
+            self.validate_fan_limits(),
+            self.validate_increments(),
+            self.validate_curve(),
+            self.validate_curve_temps(),
+            self.validate_sequences(),
+            self.validate_speed_mask(),
+        ] {
+            if let Err(error) = result {
+                errors.push(error);
+            }
+        }
+        if errors.is_empty() {
+            return Ok(());
+        }
+        return Err(ConfigError::InvalidValuesError(errors));
+    }
+
+    // Validate that fan speed increments fit between minimum and maximum speed.
+    pub fn validate_increments(&self) -> Result<(), ConfigError> {
+        let range: u8 = self.fan_speed_max.saturating_sub(self.fan_speed_min);
+        for (field, increment) in [
+            ("fan_raise_increment", self.fan_raise_increment),
+            ("fan_lower_increment", self.fan_lower_increment),
+        ] {
+            if increment == 0 {
+                return Err(ConfigError::InvalidValueError(
+                    field.to_string(),
+                    "must be at least 1 %".to_string(),
+                ));
+            }
+            if range < increment {
+                return Err(ConfigError::InvalidValueError(
+                    field.to_string(),
+                    format!(
+                        "{} % must be at most difference of maximum and minimum speed {} %",
+                        increment, range
+                    ),
+                ));
+            }
+        }
+        return Ok(());
+    }
+
+    // Validate that refresh rate can be used to compute reaction loops.
+    pub fn validate_refresh_rate(&self) -> Result<(), ConfigError> {
+        if self.refresh_rate == 0 {
+            return Err(ConfigError::InvalidValueError(
+                "refresh_rate".to_string(),
+                "must be over 0 ms".to_string(),
+            ));
+        }
+        return Ok(());
+    }
+
+    // Wait this many loops, at least one, to react to temperature changes for given milliseconds.
+    pub fn reaction_loops(&self, reaction_time_ms: u64) -> u64 {
+        return std::cmp::max(reaction_time_ms / std::cmp::max(self.refresh_rate, 1), 1);
+    }
+
+    // Wait this many loops before reacting so that reaction happens on loop at given milliseconds,
+    // zero and times under refresh rate react on first loop.
+    pub fn delay_loops(&self, delay_ms: u64) -> u64 {
+        return (delay_ms / std::cmp::max(self.refresh_rate, 1)).saturating_sub(1);
+    }
+
+    // Validate that minimum temperature is under maximum temperature.
+    pub fn validate_temp_range(&self) -> Result<(), InvalidTempRangeError> {
+        if self.max_temp <= self.min_temp {
+            return Err(InvalidTempRangeError::new(self.min_temp, self.max_temp));
+        }
+        return Ok(());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Configuration written as TOML parses back to same configuration.
+    #[test]
+    fn config_toml_round_trip() {
+        let config: Config = Config {
+            min_temp: 60,
+            curve: Some(FanCurve {
+                points: vec![(50, 30), (70, 50), (85, 100)],
+            }),
+            passive_below: Some(45),
+            fan_off_sequence: Some(vec![0x99, 0x01, 0x00]),
+            fans: vec![FanLimits {
+                id: 2,
+                min: Some(40),
+                max: None,
+            }],
+            pid_kp: 2.5,
+            ..Config::default()
+        };
+        let text: String = toml::to_string(&config).unwrap();
+        let parsed: Config = toml::from_str(&text).unwrap();
+        assert_eq!(toml::to_string(&parsed).unwrap(), text);
+        assert_eq!(parsed.min_temp, 60);
+        assert_eq!(
+            parsed.curve.unwrap().points,
+            vec![(50, 30), (70, 50), (85, 100)]
+        );
+        assert_eq!(parsed.fans[0].min, Some(40));
+    }
+
+    // Refresh rate longer than reaction time still waits one loop.
+    #[test]
+    fn reaction_loops_at_least_one() {
+        let mut config: Config = Config {
+            refresh_rate: 5000,
+            ..Config::default()
+        };
+        assert_eq!(config.reaction_loops(1000), 1);
+        assert_eq!(config.reaction_loops(0), 1);
+        assert_eq!(config.reaction_loops(5000), 1);
+        assert_eq!(config.reaction_loops(10000), 2);
+
+        // Zero refresh rate does not divide by zero.
+        config.refresh_rate = 0;
+        assert_eq!(config.reaction_loops(1000), 1000);
+    }
+
+    // Zero refresh rate is rejected and smallest positive one accepted.
+    #[test]
+    fn refresh_rate_boundaries() {
+        let mut config: Config = Config {
+            refresh_rate: 0,
+            ..Config::default()
+        };
+        assert!(config.validate_refresh_rate().is_err());
+        assert!(config.validate().is_err());
+        config.refresh_rate = 1;
+        assert!(config.validate_refresh_rate().is_ok());
+    }
+
+    // Minimum temperature must be strictly under maximum temperature.
+    #[test]
+    fn temp_range_boundaries() {
+        let mut config: Config = Config {
+            min_temp: 85,
+            max_temp: 85,
+            ..Config::default()
+        };
+        assert!(config.validate_temp_range().is_err());
+        assert!(config.validate().is_err());
+        config.min_temp = 84;
+        assert!(config.validate_temp_range().is_ok());
+        config.min_temp = 86;
+        assert!(config.validate_temp_range().is_err());
+    }
+
+    // Per fan minimum speed must be strictly under maximum speed.
+    #[test]
+    fn fan_speed_limit_boundaries() {
+        let mut config: Config = Config {
+            fans: vec![FanLimits {
+                id: 1,
+                min: Some(50),
+                max: Some(50),
+            }],
+            ..Config::default()
+        };
+        assert!(config.validate_fan_limits().is_err());
+        config.fans[0].max = Some(51);
+        assert!(config.validate_fan_limits().is_ok());
+        config.fans[0].max = Some(49);
+        assert!(config.validate_fan_limits().is_err());
+    }
+
+    // Curve speeds may reach but not pass speed limits.
+    #[test]
+    fn curve_speed_boundaries() {
+        let mut config: Config = Config {
+            curve: Some(FanCurve {
+                points: vec![(50, FAN_SPEED_MIN), (80, FAN_SPEED_MAX)],
+            }),
+            ..Config::default()
+        };
+        assert!(config.validate_curve().is_ok());
+        config.curve = Some(FanCurve {
+            points: vec![(50, FAN_SPEED_MIN - 1), (80, FAN_SPEED_MAX)],
+        });
+        assert!(config.validate_curve().is_err());
+    }
+
+    // Default critical temperature follows higher maximum temperature, set one must not be under it.
+    #[test]
+    fn critical_temp_boundaries() {
+        let mut config: Config = Config {
+            max_temp: 95,
+            ..Config::default()
+        };
+        assert!(config.validate().is_ok());
+        assert_eq!(config.critical_temp(), 95);
+        config.max_temp = MAX_TEMP;
+        assert_eq!(config.critical_temp(), CRITICAL_TEMP);
+
+        config.critical_temp = Some(MAX_TEMP);
+        assert!(config.validate_critical_temp().is_ok());
+        config.critical_temp = Some(MAX_TEMP - 1);
+        assert!(config.validate_critical_temp().is_err());
+    }
+
+    // Curve temperatures may reach but not pass minimum and maximum temperature.
+    #[test]
+    fn curve_temp_boundaries() {
+        let mut config: Config = Config {
+            min_temp: 50,
+            max_temp: 85,
+            curve: Some(FanCurve {
+                points: vec![(50, 30), (85, 100)],
+            }),
+            ..Config::default()
+        };
+        assert!(config.validate_curve_temps().is_ok());
+        assert!(config.validate().is_ok());
+        config.curve = Some(FanCurve {
+            points: vec![(49, 30), (86, 100)],
+        });
+        assert!(config.validate_curve_temps().is_err());
+        assert!(config.validate().is_err());
+    }
+
+    // Speed mask must be contiguous bits starting at speed shift.
+    #[test]
+    fn speed_mask_boundaries() {
+        let mut config: Config = Config::default();
+        for (mask, shift) in [(0xFF, 0), (0x0F, 0), (0xF0, 4), (0x3C, 2), (0x80, 7)] {
+            config.speed_mask = mask;
+            config.speed_shift = shift;
+            assert!(config.validate_speed_mask().is_ok(), "mask {:#04x}", mask);
+        }
+        for (mask, shift) in [(0x00, 0), (0xF0, 0), (0xF0, 5), (0x0F, 2), (0xA0, 5)] {
+            config.speed_mask = mask;
+            config.speed_shift = shift;
+            assert!(config.validate_speed_mask().is_err(), "mask {:#04x}", mask);
+        }
+        assert!(config.validate().is_err());
+    }
+}
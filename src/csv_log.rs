@@ -0,0 +1,85 @@
+// How many rows to write before flushing CSV file.
+pub const CSV_FLUSH_ROWS: u32 = 20;
+
+// Header of CSV file.
+const CSV_HEADER: &str = "timestamp,fan,temp,fan_speed,rpm";
+
+// Appends temperatures and fan speeds to CSV file,
+// disabling itself instead of failing when file can not be written.
+#[derive(Debug)]
+pub struct CsvLog {
+    path: std::path::PathBuf,
+    writer: Option<std::io::BufWriter<std::fs::File>>,
+    // How many rows have been written since last flush.
+    rows_since_flush: u32,
+}
+impl CsvLog {
+    // Open CSV file for appending, writing header if file is new.
+    pub fn open(path: &std::path::Path) -> CsvLog {
+        let mut csv_log: CsvLog = CsvLog {
+            path: path.to_path_buf(),
+            writer: None,
+            rows_since_flush: 0,
+        };
+        let exists: bool = path.exists();
+        match std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+        {
+            Ok(file) => {
+                csv_log.writer = Some(std::io::BufWriter::new(file));
+                if !exists {
+                    csv_log.write_line(CSV_HEADER);
+                }
+            }
+            Err(error) => csv_log.disable(error),
+        }
+        return csv_log;
+    }
+
+    // Append row of values, timestamped as seconds since epoch.
+    pub fn write_row(&mut self, fan_id: u8, temp: u8, fan_speed: u8, rpm: Option<u16>) {
+        let timestamp: f64 =
+            match std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH) {
+                Ok(duration) => duration.as_secs_f64(),
+                Err(_) => 0.0,
+            };
+        let rpm: String = match rpm {
+            Some(rpm) => rpm.to_string(),
+            None => String::new(),
+        };
+        self.write_line(&format!(
+            "{:.3},{},{},{},{}",
+            timestamp, fan_id, temp, fan_speed, rpm
+        ));
+    }
+
+    // Write line and flush every now and then.
+    fn write_line(&mut self, line: &str) {
+        let writer: &mut std::io::BufWriter<std::fs::File> = match &mut self.writer {
+            Some(writer) => writer,
+            None => return,
+        };
+        if let Err(error) = std::io::Write::write_all(writer, format!("{}\n", line).as_bytes()) {
+            return self.disable(error);
+        }
+        self.rows_since_flush += 1;
+        if CSV_FLUSH_ROWS <= self.rows_since_flush {
+            self.rows_since_flush = 0;
+            if let Err(error) = std::io::Write::flush(writer) {
+                self.disable(error);
+            }
+        }
+    }
+
+    // Stop writing to file after error.
+    fn disable(&mut self, error: std::io::Error) {
+        log::error!(
+            "Failed to write CSV log {}, continuing without it: {}",
+            self.path.display(),
+            error
+        );
+        self.writer = None;
+    }
+}
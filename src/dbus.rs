@@ -0,0 +1,112 @@
+use crate::config::Profile;
+use crate::error::FailedToStartDbusServiceError;
+
+// Well known name that D-Bus service is requested with.
+pub const DBUS_NAME: &str = "io.github.SanteriHetekivi.FanController";
+// Object path that fan controller is served at.
+pub const DBUS_PATH: &str = "/io/github/SanteriHetekivi/FanController";
+// Interface with fan controller methods and signals.
+pub const DBUS_INTERFACE: &str = "io.github.SanteriHetekivi.FanController";
+
+// Latest state shared between control loop and D-Bus service.
+#[derive(Debug, Default)]
+struct DbusState {
+    // Temperature and fan speed of every managed fan.
+    fans: std::collections::BTreeMap<u8, (u8, u8)>,
+    // Profile asked over D-Bus that control loop has not switched to yet.
+    profile: Option<Profile>,
+}
+
+// Lock shared state, state stays usable even if holder panicked.
+fn lock(state: &std::sync::Mutex<DbusState>) -> std::sync::MutexGuard<'_, DbusState> {
+    return state.lock().unwrap_or_else(|error| error.into_inner());
+}
+
+// Fan controller object served on D-Bus.
+struct FanControllerInterface {
+    state: std::sync::Arc<std::sync::Mutex<DbusState>>,
+}
+#[zbus::interface(name = "io.github.SanteriHetekivi.FanController")]
+impl FanControllerInterface {
+    // Temperature of hottest managed fan.
+    fn get_temperature(&self) -> u8 {
+        return lock(&self.state)
+            .fans
+            .values()
+            .map(|(temp, _)| *temp)
+            .max()
+            .unwrap_or(0);
+    }
+
+    // Speed of fastest managed fan as percentage.
+    fn get_speed(&self) -> u8 {
+        return lock(&self.state)
+            .fans
+            .values()
+            .map(|(_, speed)| *speed)
+            .max()
+            .unwrap_or(0);
+    }
+
+    // Ask control loop to switch to given profile.
+    fn set_profile(&self, profile: String) -> zbus::fdo::Result<()> {
+        let profile: Profile = profile
+            .parse::<Profile>()
+            .map_err(zbus::fdo::Error::InvalidArgs)?;
+        lock(&self.state).profile = Some(profile);
+        return Ok(());
+    }
+
+    // Temperature of hottest managed fan, emitted every loop.
+    #[zbus(signal)]
+    async fn temperature_changed(ctxt: &zbus::SignalContext<'_>, temp: u8) -> zbus::Result<()>;
+}
+
+// D-Bus service on system bus, connection handles method calls on its own background thread.
+pub struct DbusService {
+    connection: zbus::blocking::Connection,
+    state: std::sync::Arc<std::sync::Mutex<DbusState>>,
+}
+impl DbusService {
+    // Connect to system bus and serve fan controller.
+    pub fn start() -> Result<DbusService, FailedToStartDbusServiceError> {
+        let state: std::sync::Arc<std::sync::Mutex<DbusState>> =
+            std::sync::Arc::new(std::sync::Mutex::new(DbusState::default()));
+        let interface: FanControllerInterface = FanControllerInterface {
+            state: state.clone(),
+        };
+        let connection: zbus::blocking::Connection = zbus::blocking::connection::Builder::system()
+            .and_then(|builder| builder.name(DBUS_NAME))
+            .and_then(|builder| builder.serve_at(DBUS_PATH, interface))
+            .and_then(|builder| builder.build())
+            .map_err(|error| FailedToStartDbusServiceError::new(error.to_string()))?;
+        log::info!("Serving fan controller on D-Bus as {}", DBUS_NAME);
+        return Ok(DbusService { connection, state });
+    }
+
+    // Update latest temperature and speed of fan.
+    pub fn update(&self, fan_id: u8, temp: u8, fan_speed: u8) {
+        lock(&self.state).fans.insert(fan_id, (temp, fan_speed));
+    }
+
+    // Emit temperature of hottest managed fan.
+    pub fn emit(&self) {
+        let temp: Option<u8> = lock(&self.state).fans.values().map(|(temp, _)| *temp).max();
+        if let Some(temp) = temp {
+            if let Err(error) = self.connection.emit_signal(
+                None::<&str>,
+                DBUS_PATH,
+                DBUS_INTERFACE,
+                "TemperatureChanged",
+                &(temp,),
+            ) {
+                log::warn!("Failed to emit D-Bus signal: {}", error);
+            }
+        }
+    }
+
+    // Take profile asked over D-Bus since last call.
+    pub fn take_profile(&self) -> Option<Profile> {
+        return lock(&self.state).profile.take();
+    }
+}
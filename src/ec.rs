@@ -0,0 +1,532 @@
+// Embedded controller is accessed through x86 IO ports, so fail early with clear error elsewhere.
+#[cfg(not(target_arch = "x86_64"))]
+compile_error!("ns50mu_fan_controller_rs only supports x86_64 targets, embedded controller is accessed through x86 IO ports.");
+
+use crate::error::{
+    CommandFlagWaitTimedOutError, EcLockTimedOutError, FailedToSetIOPermissionError,
+};
+
+// Default embedded controller command port.
+pub const EC_COMMAND_PORT: u16 = 0x66;
+// Default embedded controller data port.
+pub const EC_DATA_PORT: u16 = 0x62;
+
+// Command to get temperature.
+pub const COMMAND_TEMP: u8 = 0x9E;
+// Command to set speed.
+pub const COMMAND_SPEED: u8 = 0x99;
+// Command to get fan RPM as two bytes, high byte first.
+pub const COMMAND_RPM: u8 = 0x9D;
+
+// Command to read byte from embedded controller RAM.
+pub const COMMAND_READ: u8 = 0x80;
+// Embedded controller RAM address of first fan duty, following fans are in next addresses.
+pub const REGISTER_FAN_DUTY: u8 = 0xCE;
+
+// Command to give fan control back to the firmware,
+// sent as speed command to all fans with zero speed.
+pub const COMMAND_AUTO: u8 = 0x99;
+
+// Id for default fan.
+pub const FAN_ID: u8 = 0x01;
+// Id that addresses all fans when restoring automatic control.
+pub const FAN_ID_AUTO: u8 = 0xFF;
+
+// Temperature embedded controller returns when it is not ready, for example after resume.
+pub const TEMP_INVALID: u8 = 0xFF;
+
+// How many milliseconds to wait for command flag.
+pub const COMMAND_FLAG_MAX_WAIT_MS: u64 = 1000;
+// How many microseconds to sleep between command flag reads.
+pub const COMMAND_FLAG_POLL_US: u64 = 50;
+
+// Most flag waits one command sequence does while holding lock,
+// packed speed write reads speed register with three and then sets speed with three more.
+pub const SEQUENCE_MAX_FLAG_WAITS: u64 = 6;
+// How many milliseconds to sleep between embedded controller lock tries.
+pub const LOCK_POLL_MS: u64 = 1;
+
+// How many stale bytes flush reads at most, so stuck output buffer flag can not hang it.
+pub const FLUSH_MAX_BYTES: u16 = 256;
+
+// How many times to try command sequence before giving up.
+pub const COMMAND_RETRY_ATTEMPTS: u8 = 3;
+// How many milliseconds to wait between command sequence tries.
+pub const COMMAND_RETRY_DELAY_MS: u64 = 10;
+
+// How many times to retry embedded controller init by default, for example when started before EC driver.
+pub const INIT_RETRIES: u32 = 5;
+// How many milliseconds to wait before first init retry, doubled after every failed retry.
+pub const INIT_BACKOFF_MS: u64 = 100;
+// How many milliseconds to wait between init retries at most by default.
+pub const INIT_MAX_BACKOFF_MS: u64 = 5000;
+
+// Durations of command flag waits since they were last taken.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FlagWaitTimings {
+    pub count: u32,
+    pub min: Option<std::time::Duration>,
+    pub max: Option<std::time::Duration>,
+    pub total: std::time::Duration,
+}
+impl FlagWaitTimings {
+    // Record duration of one flag wait.
+    fn record(&mut self, elapsed: std::time::Duration) {
+        self.count += 1;
+        self.min = Some(self.min.map_or(elapsed, |min| min.min(elapsed)));
+        self.max = Some(self.max.map_or(elapsed, |max| max.max(elapsed)));
+        self.total += elapsed;
+    }
+
+    // Average duration of flag waits.
+    pub fn avg(&self) -> Option<std::time::Duration> {
+        if self.count == 0 {
+            return None;
+        }
+        return Some(self.total / self.count);
+    }
+}
+
+// Flag wait durations shared by all embedded controller accesses.
+static FLAG_WAIT_TIMINGS: std::sync::Mutex<FlagWaitTimings> =
+    std::sync::Mutex::new(FlagWaitTimings {
+        count: 0,
+        min: None,
+        max: None,
+        total: std::time::Duration::ZERO,
+    });
+
+// Take flag wait durations recorded since last call.
+pub fn take_flag_wait_timings() -> FlagWaitTimings {
+    return match FLAG_WAIT_TIMINGS.lock() {
+        Ok(mut timings) => std::mem::take(&mut *timings),
+        Err(_) => FlagWaitTimings::default(),
+    };
+}
+
+// Held for whole command sequence, so sequences from different threads do not interleave.
+// Every public command sequence of Ec takes it, so do not call them from inside locked.
+static EC_LOCK: std::sync::Mutex<()> = std::sync::Mutex::new(());
+
+// Run command sequence while holding embedded controller lock.
+pub fn locked<T>(f: impl FnOnce() -> T) -> T {
+    // Poisoned lock only means other thread panicked, ports are still usable.
+    let _guard: std::sync::MutexGuard<()> = match EC_LOCK.lock() {
+        Ok(guard) => guard,
+        Err(poisoned) => poisoned.into_inner(),
+    };
+    return f();
+}
+
+// Run command sequence holding embedded controller lock, waiting for it at most given milliseconds.
+pub fn locked_within<T>(timeout_ms: u64, f: impl FnOnce() -> T) -> Result<T, EcLockTimedOutError> {
+    let start: std::time::Instant = std::time::Instant::now();
+    loop {
+        match EC_LOCK.try_lock() {
+            Ok(_guard) => return Ok(f()),
+            Err(std::sync::TryLockError::Poisoned(poisoned)) => {
+                let _guard: std::sync::MutexGuard<()> = poisoned.into_inner();
+                return Ok(f());
+            }
+            Err(std::sync::TryLockError::WouldBlock) => {
+                if u128::from(timeout_ms) < start.elapsed().as_millis() {
+                    return Err(EcLockTimedOutError::new(timeout_ms));
+                }
+                std::thread::sleep(std::time::Duration::from_millis(LOCK_POLL_MS));
+            }
+        }
+    }
+}
+
+// Run command sequence holding embedded controller lock if it is free, and without it otherwise.
+// Only for panic hook, which can not wait as lock holder may be the panicking thread itself.
+pub fn locked_or_forced<T>(f: impl FnOnce() -> T) -> T {
+    let _guard: Option<std::sync::MutexGuard<()>> = match EC_LOCK.try_lock() {
+        Ok(guard) => Some(guard),
+        Err(std::sync::TryLockError::Poisoned(poisoned)) => Some(poisoned.into_inner()),
+        Err(std::sync::TryLockError::WouldBlock) => {
+            log::warn!("Embedded controller is busy, writing without lock.");
+            None
+        }
+    };
+    return f();
+}
+
+// Set IO permission to port.
+pub fn set_port_io_permission(port: u16) -> Result<(), FailedToSetIOPermissionError> {
+    let return_value: i32 = unsafe { libc::ioperm(port as u64, 1, 1) };
+    if return_value != 0 {
+        // Read errno to tell missing permission apart from other failures.
+        let errno: i32 = unsafe { *libc::__errno_location() };
+        return Err(FailedToSetIOPermissionError::new(port, return_value, errno));
+    }
+    return Ok(());
+}
+
+// Status flags of embedded controller command port.
+#[derive(Debug, Clone)]
+pub enum Flag {
+    OBF,
+    IBF,
+}
+impl Flag {
+    // Flag as u8 value.
+    fn flag(&self) -> u8 {
+        return match self {
+            Flag::OBF => 0x1,
+            Flag::IBF => 0x2,
+        };
+    }
+
+    // Is flag on in given output?
+    fn on(&self, output: u8) -> bool {
+        let flag: u8 = self.flag();
+        return output & flag == flag;
+    }
+
+    // Wait for flag of given command port to be on.
+    fn wait_for_on(&self, port: u16, timeout_ms: u64) -> Result<(), CommandFlagWaitTimedOutError> {
+        return self.wait(port, true, timeout_ms);
+    }
+
+    // Wait for flag of given command port to be off.
+    fn wait_for_off(&self, port: u16, timeout_ms: u64) -> Result<(), CommandFlagWaitTimedOutError> {
+        return self.wait(port, false, timeout_ms);
+    }
+
+    // Wait for flag of given command port to be given on status for at most given milliseconds.
+    fn wait(
+        &self,
+        port: u16,
+        on: bool,
+        timeout_ms: u64,
+    ) -> Result<(), CommandFlagWaitTimedOutError> {
+        // Command port with readonly access.
+        let mut port_command: x86_64::instructions::port::PortGeneric<
+            u8,
+            x86_64::instructions::port::ReadOnlyAccess,
+        > = x86_64::instructions::port::PortReadOnly::<u8>::new(port);
+
+        // Init start time.
+        let start: std::time::Instant = std::time::Instant::now();
+
+        // Wait for flag to be given on status.
+        while self.on(unsafe { port_command.read() }) != on {
+            // If max timeout has been reached
+            if u128::from(timeout_ms) < start.elapsed().as_millis() {
+                // return error.
+                return Err(CommandFlagWaitTimedOutError::new(
+                    self.clone(),
+                    on,
+                    port,
+                    timeout_ms,
+                ));
+            }
+            // Sleep between reads to not saturate the CPU.
+            std::thread::sleep(std::time::Duration::from_micros(COMMAND_FLAG_POLL_US));
+        }
+
+        // Record how long waiting took.
+        let elapsed: std::time::Duration = start.elapsed();
+        log::trace!(
+            "Command flag {:?} {} after {:?}",
+            self,
+            if on { "on" } else { "off" },
+            elapsed
+        );
+        if let Ok(mut timings) = FLAG_WAIT_TIMINGS.lock() {
+            timings.record(elapsed);
+        }
+
+        // Flag was set to asked on status.
+        return Ok(());
+    }
+}
+
+// Run command sequence, retrying it on flag wait timeouts up to given attempts.
+pub fn with_retry<T>(
+    attempts: u8,
+    mut f: impl FnMut() -> Result<T, CommandFlagWaitTimedOutError>,
+) -> Result<T, CommandFlagWaitTimedOutError> {
+    let mut attempt: u8 = 1;
+    loop {
+        match f() {
+            Ok(value) => return Ok(value),
+            Err(error) => {
+                // Out of attempts.
+                if attempts <= attempt {
+                    return Err(error);
+                }
+                log::debug!("Retrying command {}/{} after: {}", attempt, attempts, error);
+                attempt += 1;
+                std::thread::sleep(std::time::Duration::from_millis(COMMAND_RETRY_DELAY_MS));
+            }
+        }
+    }
+}
+
+// Embedded controller access settings.
+#[derive(Debug, Clone, Copy)]
+pub struct Ec {
+    // How many milliseconds to wait for command flag.
+    flag_timeout_ms: u64,
+    // Port that commands are sent to and status flags read from.
+    command_port: u16,
+    // Port that data is written to and read from.
+    data_port: u16,
+    // Does firmware read raw speed inversely, so that 255 means off.
+    invert_speed: bool,
+    // Bits of speed register that hold fan speed, other bits are kept as they are.
+    speed_mask: u8,
+    // How many bits fan speed is shifted left in speed register.
+    speed_shift: u8,
+    // Raw value that firmware reads as full speed.
+    speed_max_raw: u8,
+}
+impl Ec {
+    pub fn new(
+        flag_timeout_ms: u64,
+        command_port: u16,
+        data_port: u16,
+        invert_speed: bool,
+        speed_mask: u8,
+        speed_shift: u8,
+        speed_max_raw: u8,
+    ) -> Ec {
+        Ec {
+            flag_timeout_ms,
+            command_port,
+            data_port,
+            invert_speed,
+            speed_mask,
+            speed_shift,
+            speed_max_raw,
+        }
+    }
+
+    // How many milliseconds longest command sequence can hold lock, with all of its tries timing out.
+    pub fn lock_wait_ms(&self) -> u64 {
+        let attempts: u64 = COMMAND_RETRY_ATTEMPTS as u64;
+        return self
+            .flag_timeout_ms
+            .saturating_mul(SEQUENCE_MAX_FLAG_WAITS)
+            .saturating_add(COMMAND_RETRY_DELAY_MS)
+            .saturating_mul(attempts);
+    }
+
+    // Raw value that firmware reads as full speed.
+    pub fn speed_max_raw(&self) -> u8 {
+        return self.speed_max_raw;
+    }
+
+    // Does speed register pack other bits alongside fan speed?
+    pub(crate) fn speed_packed(&self) -> bool {
+        return self.speed_mask != u8::MAX;
+    }
+
+    // Bits of speed register that hold fan speed.
+    pub(crate) fn speed_mask(&self) -> u8 {
+        return self.speed_mask;
+    }
+
+    // How many bits fan speed is shifted left in speed register.
+    pub(crate) fn speed_shift(&self) -> u8 {
+        return self.speed_shift;
+    }
+
+    // Convert raw speed between firmware and normal direction, same conversion works both ways.
+    pub(crate) fn orient_raw_speed(&self, raw: u8) -> u8 {
+        if self.invert_speed {
+            return self.speed_max_raw.saturating_sub(raw);
+        }
+        return raw;
+    }
+
+    // Initialize embedded controller.
+    pub fn init(&self) -> Result<(), FailedToSetIOPermissionError> {
+        set_port_io_permission(self.data_port)?;
+        set_port_io_permission(self.command_port)?;
+        return Ok(());
+    }
+
+    // Write given value to given port.
+    pub fn write_to_port(&self, port: u16, value: u8) -> Result<(), CommandFlagWaitTimedOutError> {
+        // Wait for input buffer flag to be off.
+        Flag::IBF
+            .wait_for_off(self.command_port, self.flag_timeout_ms)
+            .map_err(|error| error.writing(value, port))?;
+
+        // Write the value to port.
+        unsafe { x86_64::instructions::port::PortWriteOnly::<u8>::new(port).write(value) };
+
+        Ok(())
+    }
+
+    // Send command to embedded controller.
+    pub fn send_command(&self, command: u8) -> Result<(), CommandFlagWaitTimedOutError> {
+        return self.write_to_port(self.command_port, command);
+    }
+
+    // Write data to embedded controller.
+    pub fn write_data(&self, data: u8) -> Result<(), CommandFlagWaitTimedOutError> {
+        return self.write_to_port(self.data_port, data);
+    }
+
+    // Flush embedded controller by discarding stale bytes from output buffer,
+    // done before every command that reads response so it does not read old byte.
+    pub fn flush(&self) {
+        // Init readonly access to command and data ports.
+        let mut port_command: x86_64::instructions::port::PortGeneric<
+            u8,
+            x86_64::instructions::port::ReadOnlyAccess,
+        > = x86_64::instructions::port::PortReadOnly::<u8>::new(self.command_port);
+        let mut port_data: x86_64::instructions::port::PortGeneric<
+            u8,
+            x86_64::instructions::port::ReadOnlyAccess,
+        > = x86_64::instructions::port::PortReadOnly::<u8>::new(self.data_port);
+
+        // While output buffer flag is on
+        let mut discarded: u16 = 0;
+        while discarded < FLUSH_MAX_BYTES && Flag::OBF.on(unsafe { port_command.read() }) {
+            // read data.
+            let byte: u8 = unsafe { port_data.read() };
+            log::debug!("Flush discarded stale byte {:#04x}", byte);
+            discarded += 1;
+        }
+        if 0 < discarded {
+            log::debug!("Flush discarded {} stale bytes", discarded);
+        }
+    }
+
+    // Send command byte followed by data bytes of sequence, for firmware specific commands.
+    pub fn send_sequence(&self, sequence: &[u8]) -> Result<(), CommandFlagWaitTimedOutError> {
+        let (command, data): (&u8, &[u8]) = match sequence.split_first() {
+            Some(split) => split,
+            None => return Ok(()),
+        };
+        return locked(|| {
+            with_retry(COMMAND_RETRY_ATTEMPTS, || {
+                self.send_command(*command)?;
+                for byte in data {
+                    self.write_data(*byte)?;
+                }
+                return Ok(());
+            })
+        });
+    }
+
+    // Read byte from enbedded controller.
+    pub fn read_byte(&self) -> Result<u8, CommandFlagWaitTimedOutError> {
+        // Wait for output buffer flag to be on.
+        Flag::OBF.wait_for_on(self.command_port, self.flag_timeout_ms)?;
+
+        // Return read byte.
+        Ok(unsafe { x86_64::instructions::port::PortReadOnly::<u8>::new(self.data_port).read() })
+    }
+
+    // Read byte from embedded controller RAM at given address.
+    pub fn read_register(&self, address: u8) -> Result<u8, CommandFlagWaitTimedOutError> {
+        return locked(|| {
+            with_retry(COMMAND_RETRY_ATTEMPTS, || {
+                return self.read_register_unlocked(address);
+            })
+        });
+    }
+
+    // Read byte from embedded controller RAM, caller must hold embedded controller lock.
+    pub(crate) fn read_register_unlocked(
+        &self,
+        address: u8,
+    ) -> Result<u8, CommandFlagWaitTimedOutError> {
+        self.flush();
+        self.send_command(COMMAND_READ)?;
+        self.write_data(address)?;
+        return self.read_byte();
+    }
+
+    // Get temperature of sensor with given index.
+    pub fn get_temp(&self, sensor_id: u8) -> Result<u8, CommandFlagWaitTimedOutError> {
+        return locked(|| {
+            with_retry(COMMAND_RETRY_ATTEMPTS, || {
+                self.flush();
+                self.send_command(COMMAND_TEMP)?;
+                self.write_data(sensor_id)?;
+                return self.read_byte();
+            })
+        });
+    }
+
+    // Get temperature of sensor with given index as whole degrees and fraction of degree in 1/256 steps,
+    // for firmware that returns second byte after the whole degrees.
+    pub fn get_temp_fine(&self, sensor_id: u8) -> Result<(u8, u8), CommandFlagWaitTimedOutError> {
+        return locked(|| {
+            with_retry(COMMAND_RETRY_ATTEMPTS, || {
+                self.flush();
+                self.send_command(COMMAND_TEMP)?;
+                self.write_data(sensor_id)?;
+                let whole: u8 = self.read_byte()?;
+                return Ok((whole, self.read_byte()?));
+            })
+        });
+    }
+
+    // Get local temperature of fan, read from sensor with same index as fan.
+    pub fn get_local_temp(&self, fan_id: u8) -> Result<u8, CommandFlagWaitTimedOutError> {
+        return self.get_temp(fan_id);
+    }
+
+    // Get local temperature of fan as whole degrees and fraction of degree in 1/256 steps.
+    pub fn get_local_temp_fine(
+        &self,
+        fan_id: u8,
+    ) -> Result<(u8, u8), CommandFlagWaitTimedOutError> {
+        return self.get_temp_fine(fan_id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Lock held by other thread times out, free lock runs sequence.
+    #[test]
+    fn locked_within_waits_for_lock() {
+        let (held_sender, held): (std::sync::mpsc::Sender<()>, std::sync::mpsc::Receiver<()>) =
+            std::sync::mpsc::channel();
+        let (release, release_receiver): (
+            std::sync::mpsc::Sender<()>,
+            std::sync::mpsc::Receiver<()>,
+        ) = std::sync::mpsc::channel();
+        let holder: std::thread::JoinHandle<()> = std::thread::spawn(move || {
+            locked(|| {
+                held_sender.send(()).unwrap();
+                release_receiver.recv().unwrap();
+            });
+        });
+        held.recv().unwrap();
+        assert!(locked_within(20, || ()).is_err());
+
+        release.send(()).unwrap();
+        holder.join().unwrap();
+        assert_eq!(locked_within(20, || 5).unwrap(), 5);
+    }
+
+    // Lock wait covers every try of longest sequence timing out.
+    #[test]
+    fn lock_wait_covers_longest_sequence() {
+        let ec: Ec = Ec::new(
+            COMMAND_FLAG_MAX_WAIT_MS,
+            EC_COMMAND_PORT,
+            EC_DATA_PORT,
+            false,
+            u8::MAX,
+            0,
+            u8::MAX,
+        );
+        assert_eq!(
+            ec.lock_wait_ms(),
+            (COMMAND_FLAG_MAX_WAIT_MS * SEQUENCE_MAX_FLAG_WAITS + COMMAND_RETRY_DELAY_MS)
+                * COMMAND_RETRY_ATTEMPTS as u64
+        );
+    }
+}
@@ -0,0 +1,301 @@
+use crate::ec::Flag;
+
+// Configuration loading error.
+#[derive(Debug, thiserror::Error)]
+pub enum ConfigError {
+    #[error("Failed to read config file {}: {1}", .0.display())]
+    ReadError(std::path::PathBuf, #[source] std::io::Error),
+    #[error("Failed to parse config file {}: {1}", .0.display())]
+    ParseError(std::path::PathBuf, #[source] toml::de::Error),
+    #[error("Invalid config value {0}: {1}")]
+    InvalidValueError(String, String),
+    #[error("Found {} problems in config:{}", .0.len(), list_errors(.0))]
+    InvalidValuesError(Vec<ConfigError>),
+}
+
+// Format errors as lines after message.
+fn list_errors(errors: &[ConfigError]) -> String {
+    return errors
+        .iter()
+        .map(|error| format!("\n{}", error))
+        .collect::<String>();
+}
+
+// PID file error.
+#[derive(Debug, thiserror::Error)]
+pub enum PidFileError {
+    #[error("Fan controller is already running with PID {1} from {}!", .0.display())]
+    AlreadyRunningError(std::path::PathBuf, i32),
+    #[error("Failed to write PID file {}: {1}", .0.display())]
+    WriteError(std::path::PathBuf, #[source] std::io::Error),
+}
+
+// Failed to set IO permission error.
+#[derive(Debug, thiserror::Error)]
+#[error("{}", describe_io_permission(*.port, *.return_value, *.errno))]
+pub struct FailedToSetIOPermissionError {
+    port: u16,
+    return_value: i32,
+    errno: i32,
+}
+impl FailedToSetIOPermissionError {
+    pub fn new(port: u16, return_value: i32, errno: i32) -> FailedToSetIOPermissionError {
+        FailedToSetIOPermissionError {
+            port,
+            return_value,
+            errno,
+        }
+    }
+
+    // Was permission denied because process is not root?
+    pub fn permission_denied(&self) -> bool {
+        return self.errno == libc::EPERM;
+    }
+}
+
+// Describe failed IO permission request, with hint about root when permission was denied.
+fn describe_io_permission(port: u16, return_value: i32, errno: i32) -> String {
+    if errno == libc::EPERM {
+        return format!(
+            "Permission denied to port {:#x}, requires CAP_SYS_RAWIO or root to access EC ports; try sudo! (return value {}, errno {})",
+            port, return_value, errno
+        );
+    }
+    return format!(
+        "Failed to set IO permission to port {:#x} got return value {}: {}!",
+        port,
+        return_value,
+        std::io::Error::from_raw_os_error(errno)
+    );
+}
+
+// Invalid temperature range error.
+#[derive(Debug, thiserror::Error)]
+#[error("Minimum temperature {min_temp} C must be under maximum temperature {max_temp} C!")]
+pub struct InvalidTempRangeError {
+    min_temp: u8,
+    max_temp: u8,
+}
+impl InvalidTempRangeError {
+    pub fn new(min_temp: u8, max_temp: u8) -> InvalidTempRangeError {
+        InvalidTempRangeError { min_temp, max_temp }
+    }
+}
+
+// Command flag wait timed out error.
+#[derive(Debug, thiserror::Error)]
+#[error(
+    "Timed out after {waited_ms} ms when waiting for command flag {flag:?} of port {port:#x} to be {}{}!",
+    if *.on { "on" } else { "off" },
+    describe_write(.write)
+)]
+pub struct CommandFlagWaitTimedOutError {
+    flag: Flag,
+    on: bool,
+    // Command port that flag was read from.
+    port: u16,
+    // How many milliseconds was waited.
+    waited_ms: u64,
+    // Byte and port that were about to be written, when waiting before write.
+    write: Option<(u8, u16)>,
+}
+impl CommandFlagWaitTimedOutError {
+    pub fn new(flag: Flag, on: bool, port: u16, waited_ms: u64) -> CommandFlagWaitTimedOutError {
+        CommandFlagWaitTimedOutError {
+            flag,
+            on,
+            port,
+            waited_ms,
+            write: None,
+        }
+    }
+
+    // Add byte and port that were about to be written.
+    pub fn writing(mut self, value: u8, port: u16) -> CommandFlagWaitTimedOutError {
+        self.write = Some((value, port));
+        return self;
+    }
+}
+
+// Embedded controller lock wait timed out error.
+#[derive(Debug, thiserror::Error)]
+#[error("Timed out after {waited_ms} ms when waiting for embedded controller lock!")]
+pub struct EcLockTimedOutError {
+    // How many milliseconds was waited.
+    waited_ms: u64,
+}
+impl EcLockTimedOutError {
+    pub fn new(waited_ms: u64) -> EcLockTimedOutError {
+        EcLockTimedOutError { waited_ms }
+    }
+}
+
+// Collects all of the errors that can occur when setting fan speed from other thread than control loop.
+#[derive(Debug, thiserror::Error)]
+pub enum FanSpeedSetError {
+    #[error("Command flag wait timed out error:\n{0}")]
+    CommandFlagWaitTimedOutError(#[from] CommandFlagWaitTimedOutError),
+    #[error("Embedded controller lock wait timed out error:\n{0}")]
+    EcLockTimedOutError(#[from] EcLockTimedOutError),
+}
+
+// Describe write that flag was waited for.
+fn describe_write(write: &Option<(u8, u16)>) -> String {
+    return match write {
+        Some((value, port)) => format!(" before writing {:#04x} to port {:#x}", value, port),
+        None => String::new(),
+    };
+}
+
+// Failed to read sysfs temperature error.
+#[derive(Debug, thiserror::Error)]
+#[error("Failed to read temperature from {}: {message}", .path.display())]
+pub struct FailedToReadSysfsTemperatureError {
+    path: std::path::PathBuf,
+    message: String,
+}
+impl FailedToReadSysfsTemperatureError {
+    pub fn new(path: std::path::PathBuf, message: String) -> FailedToReadSysfsTemperatureError {
+        FailedToReadSysfsTemperatureError { path, message }
+    }
+}
+
+// Invalid temperature reading error.
+#[derive(Debug, thiserror::Error)]
+#[error("Embedded controller returned invalid temperature {value:#x} for sensor {sensor_id}!")]
+pub struct InvalidTemperatureReadingError {
+    sensor_id: u8,
+    value: u8,
+}
+impl InvalidTemperatureReadingError {
+    pub fn new(sensor_id: u8, value: u8) -> InvalidTemperatureReadingError {
+        InvalidTemperatureReadingError { sensor_id, value }
+    }
+}
+
+// Collects all of the errors that can occur when reading temperature.
+#[derive(Debug, thiserror::Error)]
+pub enum TemperatureReadError {
+    #[error("Command flag wait timed out error:\n{0}")]
+    CommandFlagWaitTimedOutError(#[from] CommandFlagWaitTimedOutError),
+    #[error("Failed to read sysfs temperature error:\n{0}")]
+    FailedToReadSysfsTemperatureError(#[from] FailedToReadSysfsTemperatureError),
+    #[error("Invalid temperature reading error:\n{0}")]
+    InvalidTemperatureReadingError(#[from] InvalidTemperatureReadingError),
+}
+
+// Failed to start metrics server error.
+#[derive(Debug, thiserror::Error)]
+#[error("Failed to start metrics server at {addr}: {message}")]
+pub struct FailedToStartMetricsServerError {
+    addr: String,
+    message: String,
+}
+impl FailedToStartMetricsServerError {
+    pub fn new(addr: String, message: String) -> FailedToStartMetricsServerError {
+        FailedToStartMetricsServerError { addr, message }
+    }
+}
+
+// Failed to start status socket error.
+#[derive(Debug, thiserror::Error)]
+#[error("Failed to start status socket at {}: {message}", .path.display())]
+pub struct FailedToStartStatusSocketError {
+    path: std::path::PathBuf,
+    message: String,
+}
+impl FailedToStartStatusSocketError {
+    pub fn new(path: std::path::PathBuf, message: String) -> FailedToStartStatusSocketError {
+        FailedToStartStatusSocketError { path, message }
+    }
+}
+
+// Failed to capture fan curve error.
+#[derive(Debug, thiserror::Error)]
+#[error("Failed to capture fan curve to {}: {message}", .path.display())]
+pub struct FailedToCaptureError {
+    path: std::path::PathBuf,
+    message: String,
+}
+impl FailedToCaptureError {
+    pub fn new(path: std::path::PathBuf, message: String) -> FailedToCaptureError {
+        FailedToCaptureError { path, message }
+    }
+}
+
+// Diagnostic checks failed error.
+#[derive(Debug, thiserror::Error)]
+#[error("{failed} of {total} diagnostic checks failed!")]
+pub struct DiagnosticsFailedError {
+    failed: u32,
+    total: u32,
+}
+impl DiagnosticsFailedError {
+    pub fn new(failed: u32, total: u32) -> DiagnosticsFailedError {
+        DiagnosticsFailedError { failed, total }
+    }
+}
+
+// Failed to start D-Bus service error.
+#[derive(Debug, thiserror::Error)]
+#[error("Failed to start D-Bus service: {message}")]
+pub struct FailedToStartDbusServiceError {
+    message: String,
+}
+impl FailedToStartDbusServiceError {
+    pub fn new(message: String) -> FailedToStartDbusServiceError {
+        FailedToStartDbusServiceError { message }
+    }
+}
+
+// Failed to draw monitor to terminal error.
+#[derive(Debug, thiserror::Error)]
+#[error("Failed to draw monitor: {message}")]
+pub struct FailedToDrawMonitorError {
+    message: String,
+}
+impl FailedToDrawMonitorError {
+    pub fn new(message: String) -> FailedToDrawMonitorError {
+        FailedToDrawMonitorError { message }
+    }
+}
+
+// Failed to read temperature trace error.
+#[derive(Debug, thiserror::Error)]
+#[error("Failed to read temperature trace {}: {message}", .path.display())]
+pub struct FailedToReadTraceError {
+    path: std::path::PathBuf,
+    message: String,
+}
+impl FailedToReadTraceError {
+    pub fn new(path: std::path::PathBuf, message: String) -> FailedToReadTraceError {
+        FailedToReadTraceError { path, message }
+    }
+}
+
+// Collects all of the errors that can occur when creating a new connection.
+#[derive(Debug, thiserror::Error)]
+pub enum RunError {
+    #[error("Failed to set IO permission error:\n{0}")]
+    FailedToSetIOPermissionError(#[from] FailedToSetIOPermissionError),
+    #[error("Command flag wait timed out error:\n{0}")]
+    CommandFlagWaitTimedOutError(#[from] CommandFlagWaitTimedOutError),
+    #[error("Config error:\n{0}")]
+    ConfigError(#[from] ConfigError),
+    #[error("Failed to start metrics server error:\n{0}")]
+    FailedToStartMetricsServerError(#[from] FailedToStartMetricsServerError),
+    #[error("Temperature read error:\n{0}")]
+    TemperatureReadError(#[from] TemperatureReadError),
+    #[error("Failed to start status socket error:\n{0}")]
+    FailedToStartStatusSocketError(#[from] FailedToStartStatusSocketError),
+    #[error("Failed to read trace error:\n{0}")]
+    FailedToReadTraceError(#[from] FailedToReadTraceError),
+    #[error("Failed to capture error:\n{0}")]
+    FailedToCaptureError(#[from] FailedToCaptureError),
+    #[error("Diagnostics failed error:\n{0}")]
+    DiagnosticsFailedError(#[from] DiagnosticsFailedError),
+    #[error("Failed to start D-Bus service error:\n{0}")]
+    FailedToStartDbusServiceError(#[from] FailedToStartDbusServiceError),
+    #[error("Failed to draw monitor error:\n{0}")]
+    FailedToDrawMonitorError(#[from] FailedToDrawMonitorError),
+}
@@ -0,0 +1,1426 @@
+use crate::config::{
+    Config, Mode, ADAPTIVE_LAG_RAISES, ADAPTIVE_REVERSAL_MS, ADAPTIVE_SCALE_MAX,
+    ADAPTIVE_SCALE_MIN, ADAPTIVE_SCALE_STEP, FAN_SPEED_MAX, KEEPING_LOG_MS,
+    TURBO_REACTION_TIME_MS_RAISE,
+};
+use crate::ec::{
+    locked, locked_or_forced, locked_within, with_retry, Ec, COMMAND_AUTO, COMMAND_RETRY_ATTEMPTS,
+    COMMAND_RPM, COMMAND_SPEED, FAN_ID_AUTO, REGISTER_FAN_DUTY,
+};
+use crate::error::{CommandFlagWaitTimedOutError, FanSpeedSetError};
+use crate::temperature::{celsius_to_tenths, format_tenths};
+
+// Last fan speed before anything has been written, so that first decision always writes.
+pub const FAN_SPEED_UNKNOWN: u8 = u8::MAX;
+
+// Convert speed percentage to raw value from 0 to max raw written to embedded controller.
+// Percentages over 100 are clamped and result is rounded down, so 30 % of 255 becomes 76.
+pub fn percent_to_raw(speed: u8, max_raw: u8) -> u8 {
+    // Integer math, float rounding would floor 53 % of 100 to 52.
+    return (std::cmp::min(speed, 100) as u16 * max_raw as u16 / 100) as u8;
+}
+
+// Convert raw value from 0 to max raw read from embedded controller to speed percentage.
+// Result is rounded to nearest, while one raw step is under half percent this gives back
+// the exact percentage for every value made by percent_to_raw, 76 of 255 becomes 30 %.
+pub fn raw_to_percent(raw: u8, max_raw: u8) -> u8 {
+    let max_raw: u8 = std::cmp::max(max_raw, 1);
+    return ((std::cmp::min(raw, max_raw) as f32 / max_raw as f32) * 100_f32).round() as u8;
+}
+
+// Put raw speed from 0 to max raw scaled to width of masked bits into register, keeping its other bits.
+pub fn pack_speed(register: u8, raw: u8, max_raw: u8, mask: u8, shift: u8) -> u8 {
+    let field_max: u8 = mask.checked_shr(shift as u32).unwrap_or(0);
+    let field: u8 = (std::cmp::min(raw, max_raw) as u16 * field_max as u16
+        / std::cmp::max(max_raw, 1) as u16) as u8;
+    return (register & !mask) | (field.checked_shl(shift as u32).unwrap_or(0) & mask);
+}
+
+// Take masked bits of register and scale them back to raw speed from 0 to max raw.
+pub fn unpack_speed(register: u8, max_raw: u8, mask: u8, shift: u8) -> u8 {
+    let field_max: u8 = mask.checked_shr(shift as u32).unwrap_or(0);
+    if field_max == 0 {
+        return 0;
+    }
+    let field: u8 = (register & mask).checked_shr(shift as u32).unwrap_or(0);
+    return ((field as u16 * max_raw as u16 + field_max as u16 / 2) / field_max as u16) as u8;
+}
+
+// Register of embedded controller RAM that holds speed of given fan.
+fn fan_duty_register(fan_id: u8) -> u8 {
+    return REGISTER_FAN_DUTY.wrapping_add(fan_id.saturating_sub(1));
+}
+
+// Fan speed commands of embedded controller.
+impl Ec {
+    // Set data speed, keeping other bits of speed register when it packs them alongside speed.
+    pub fn set_fan_speed(&self, fan_id: u8, speed: u8) -> Result<(), CommandFlagWaitTimedOutError> {
+        return locked(|| self.set_fan_speed_unlocked(fan_id, speed));
+    }
+
+    // Set fan speed from other thread, waiting for embedded controller lock as long as
+    // longest command sequence can hold it, so that bytes never go in middle of other sequence.
+    pub fn set_fan_speed_waiting(&self, fan_id: u8, speed: u8) -> Result<(), FanSpeedSetError> {
+        let result: Result<(), CommandFlagWaitTimedOutError> =
+            locked_within(self.lock_wait_ms(), || {
+                self.set_fan_speed_unlocked(fan_id, speed)
+            })?;
+        return Ok(result?);
+    }
+
+    // Set fan speed from panic hook without waiting for embedded controller lock.
+    pub fn force_fan_speed(
+        &self,
+        fan_id: u8,
+        speed: u8,
+    ) -> Result<(), CommandFlagWaitTimedOutError> {
+        return locked_or_forced(|| self.set_fan_speed_unlocked(fan_id, speed));
+    }
+
+    // Set fan speed, caller must hold embedded controller lock.
+    fn set_fan_speed_unlocked(
+        &self,
+        fan_id: u8,
+        speed: u8,
+    ) -> Result<(), CommandFlagWaitTimedOutError> {
+        return with_retry(COMMAND_RETRY_ATTEMPTS, || {
+            let mut raw: u8 = self.orient_raw_speed(percent_to_raw(speed, self.speed_max_raw()));
+            if self.speed_packed() {
+                let register: u8 = self.read_register_unlocked(fan_duty_register(fan_id))?;
+                raw = pack_speed(
+                    register,
+                    raw,
+                    self.speed_max_raw(),
+                    self.speed_mask(),
+                    self.speed_shift(),
+                );
+            }
+            self.send_command(COMMAND_SPEED)?;
+            self.write_data(fan_id)?;
+            return self.write_data(raw);
+        });
+    }
+
+    // Get fan speed percentage stored in embedded controller.
+    pub fn get_fan_speed(&self, fan_id: u8) -> Result<u8, CommandFlagWaitTimedOutError> {
+        let mut raw: u8 = self.read_register(fan_duty_register(fan_id))?;
+        if self.speed_packed() {
+            raw = unpack_speed(
+                raw,
+                self.speed_max_raw(),
+                self.speed_mask(),
+                self.speed_shift(),
+            );
+        }
+        return Ok(raw_to_percent(
+            self.orient_raw_speed(raw),
+            self.speed_max_raw(),
+        ));
+    }
+
+    // Give fan control back to the firmware.
+    pub fn restore_auto_fan(&self) -> Result<(), CommandFlagWaitTimedOutError> {
+        return locked(|| {
+            return with_retry(COMMAND_RETRY_ATTEMPTS, || {
+                self.send_command(COMMAND_AUTO)?;
+                self.write_data(FAN_ID_AUTO)?;
+                return self.write_data(0x00);
+            });
+        });
+    }
+
+    // Get fan RPM.
+    pub fn get_fan_rpm(&self, fan_id: u8) -> Result<u16, CommandFlagWaitTimedOutError> {
+        return locked(|| {
+            self.flush();
+            self.send_command(COMMAND_RPM)?;
+            self.write_data(fan_id)?;
+            let high: u8 = self.read_byte()?;
+            let low: u8 = self.read_byte()?;
+            return Ok(u16::from_be_bytes([high, low]));
+        });
+    }
+}
+
+// Sink that fan speeds are written to.
+pub trait FanSpeedSink {
+    // Set fan speed as percentage.
+    fn set(&mut self, percent: u8) -> Result<(), CommandFlagWaitTimedOutError>;
+}
+
+// Fan controlled through embedded controller.
+#[derive(Debug)]
+pub struct EcFan {
+    ec: Ec,
+    fan_id: u8,
+    // Sequence sent instead of speed 0 on firmware that does not stop fan with it.
+    off_sequence: Option<Vec<u8>>,
+    // Sequence sent before first speed after fan was stopped with off sequence.
+    resume_sequence: Option<Vec<u8>>,
+    // Has fan been stopped with off sequence.
+    off: bool,
+}
+impl EcFan {
+    pub fn new(
+        ec: Ec,
+        fan_id: u8,
+        off_sequence: Option<Vec<u8>>,
+        resume_sequence: Option<Vec<u8>>,
+    ) -> EcFan {
+        EcFan {
+            ec,
+            fan_id,
+            off_sequence,
+            resume_sequence,
+            off: false,
+        }
+    }
+}
+impl FanSpeedSink for EcFan {
+    fn set(&mut self, percent: u8) -> Result<(), CommandFlagWaitTimedOutError> {
+        if percent == 0 {
+            if let Some(off_sequence) = &self.off_sequence {
+                log::debug!("Stopping fan {} with off sequence", self.fan_id);
+                self.ec.send_sequence(off_sequence)?;
+                self.off = true;
+                return Ok(());
+            }
+        }
+        if self.off {
+            if let Some(resume_sequence) = &self.resume_sequence {
+                log::debug!("Resuming fan {} with resume sequence", self.fan_id);
+                self.ec.send_sequence(resume_sequence)?;
+            }
+            self.off = false;
+        }
+        return self.ec.set_fan_speed(self.fan_id, percent);
+    }
+}
+
+// Fan that only logs speeds that would be set.
+#[derive(Debug)]
+pub struct DryRunFan {
+    fan_id: u8,
+}
+impl DryRunFan {
+    pub fn new(fan_id: u8) -> DryRunFan {
+        DryRunFan { fan_id }
+    }
+}
+impl FanSpeedSink for DryRunFan {
+    fn set(&mut self, percent: u8) -> Result<(), CommandFlagWaitTimedOutError> {
+        log::info!("Would set fan {} to {} %", self.fan_id, percent);
+        return Ok(());
+    }
+}
+
+// Fan that records speeds set to it, for tests.
+#[cfg(test)]
+#[derive(Debug, Default)]
+pub struct RecordingFan {
+    pub speeds: Vec<u8>,
+}
+#[cfg(test)]
+impl FanSpeedSink for RecordingFan {
+    fn set(&mut self, percent: u8) -> Result<(), CommandFlagWaitTimedOutError> {
+        self.speeds.push(percent);
+        return Ok(());
+    }
+}
+
+// Piecewise linear fan curve of (temperature, speed) points ordered by temperature.
+#[derive(Debug, Clone, serde::Deserialize, serde::Serialize)]
+#[serde(transparent)]
+pub struct FanCurve {
+    pub points: Vec<(u8, u8)>,
+}
+impl FanCurve {
+    // Speed for given temperature interpolated between curve points
+    // and clamped to first and last point.
+    pub fn speed_for(&self, temp: u8) -> u8 {
+        return self.speed_for_tenths(celsius_to_tenths(temp));
+    }
+
+    // Speed for given temperature in tenths of degree, interpolated between curve points.
+    pub fn speed_for_tenths(&self, temp: u16) -> u8 {
+        // Without points run at max speed to be safe.
+        let (first_temp, first_speed): (u8, u8) = match self.points.first() {
+            Some(point) => *point,
+            None => return FAN_SPEED_MAX,
+        };
+        if temp <= celsius_to_tenths(first_temp) {
+            return first_speed;
+        }
+
+        // Find points that temperature is between and interpolate speed.
+        for window in self.points.windows(2) {
+            let (temp_low, speed_low): (u16, u8) = (celsius_to_tenths(window[0].0), window[0].1);
+            let (temp_high, speed_high): (u16, u8) = (celsius_to_tenths(window[1].0), window[1].1);
+            if temp <= temp_high {
+                if temp_high == temp_low {
+                    return speed_high;
+                }
+                let speed: i32 = speed_low as i32
+                    + (speed_high as i32 - speed_low as i32) * (temp as i32 - temp_low as i32)
+                        / (temp_high as i32 - temp_low as i32);
+                return speed as u8;
+            }
+        }
+
+        // Over last point.
+        return self.points[self.points.len() - 1].1;
+    }
+}
+
+// PID controller that gives fan speed from distance to setpoint temperature.
+#[derive(Debug, Clone)]
+pub struct Pid {
+    kp: f32,
+    ki: f32,
+    kd: f32,
+    // Accumulated error over time.
+    integral: f32,
+    // Error of last update.
+    last_error: Option<f32>,
+}
+impl Pid {
+    pub fn new(kp: f32, ki: f32, kd: f32) -> Pid {
+        Pid {
+            kp,
+            ki,
+            kd,
+            integral: 0.0,
+            last_error: None,
+        }
+    }
+
+    // Update controller with celsius temperature after given seconds and return fan speed percentage.
+    pub fn update(&mut self, setpoint: u8, temp: f32, dt: f32) -> u8 {
+        // Positive error when temperature is over setpoint.
+        let error: f32 = temp - setpoint as f32;
+
+        // Accumulate error, limiting it so integral term alone stays within speed range.
+        self.integral += error * dt;
+        if 0.0 < self.ki {
+            self.integral = self.integral.clamp(0.0, 100.0 / self.ki);
+        }
+
+        // Rate of error change since last update.
+        let derivative: f32 = match self.last_error {
+            Some(last_error) if 0.0 < dt => (error - last_error) / dt,
+            _ => 0.0,
+        };
+        self.last_error = Some(error);
+
+        let output: f32 = self.kp * error + self.ki * self.integral + self.kd * derivative;
+        return output.clamp(0.0, 100.0) as u8;
+    }
+}
+
+// Zone of semi-passive control given by stop and start temperatures.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Zone {
+    // Fan speed is decided by control mode.
+    Running,
+    // Temperature dropped under start temperature, so fan is kept at minimum speed.
+    Idling,
+    // Temperature dropped under stop temperature, so fan is stopped until it rises over start temperature.
+    Stopped,
+}
+
+// Fan controller that decides fan speed from temperatures.
+#[derive(Debug)]
+pub struct FanController {
+    // Configuration with thresholds.
+    config: Config,
+    // Wait this many loops to change speed when temperature is raising.
+    reaction_loops_raise: u64,
+    // Wait this many loops to change speed when temperature is lowering or staying same.
+    reaction_loops_lower: u64,
+    // Set to this fan speed.
+    fan_speed: u8,
+    // Speed of fan when last set.
+    fan_speed_last: u8,
+    // Last loop temperature in tenths of degree.
+    temp_last: u16,
+    // How many loops has temperature been raising.
+    raising_loops: u64,
+    // How many loops has temperature been lowering or staying the same.
+    lowering_or_staying_loops: u64,
+    // Is fan turned off because temperature is under passive threshold.
+    passive: bool,
+    // Zone of semi-passive control, always running when stop temperature is unset.
+    zone: Zone,
+    // Temperature in tenths of degree that last raised fan speed.
+    raise_temp: Option<u16>,
+    // PID controller used in PID mode.
+    pid: Pid,
+    // How many loops of cooldown are left before lowering, unset until lowering is first wanted.
+    cooldown_loops: Option<u64>,
+    // How many loops since fan was last started or stopped by passive mode.
+    passive_transition_loops: u64,
+    // Is system idle, so that fan is lowered slower and kept under idle ceiling.
+    idle: bool,
+    // Is turbo on, so that fan can go to max speed and is raised faster.
+    turbo: bool,
+    // How many loops fan speed has been kept the same.
+    keeping_loops: u64,
+    // Latest share of time CPU was busy, from 0 to 1.
+    cpu_utilization: f32,
+    // Reaction times scaled by adaptive mode, as percentage of configured ones.
+    adaptive_scale: u32,
+    // Did last fan speed change raise it, unset before first change.
+    adaptive_raised: Option<bool>,
+    // How many loops since fan speed last changed.
+    adaptive_change_loops: u64,
+    // How many times in a row fan speed has been raised.
+    adaptive_raises: u32,
+}
+impl FanController {
+    pub fn new(config: &Config, temp: u8) -> FanController {
+        FanController {
+            config: config.clone(),
+            reaction_loops_raise: config.reaction_loops(config.reaction_time_ms_raise),
+            reaction_loops_lower: config.reaction_loops(config.reaction_time_ms_lower),
+            fan_speed: config.fan_speed_min,
+            fan_speed_last: 0,
+            temp_last: celsius_to_tenths(temp),
+            raising_loops: 0,
+            lowering_or_staying_loops: 0,
+            passive: false,
+            zone: Zone::Running,
+            raise_temp: None,
+            pid: Pid::new(config.pid_kp, config.pid_ki, config.pid_kd),
+            cooldown_loops: None,
+            // Fan has not been started or stopped yet, so do not hold it in either state.
+            passive_transition_loops: u64::MAX,
+            idle: false,
+            turbo: false,
+            keeping_loops: 0,
+            cpu_utilization: 0.0,
+            adaptive_scale: 100,
+            adaptive_raised: None,
+            adaptive_change_loops: 0,
+            adaptive_raises: 0,
+        }
+    }
+
+    // Set reaction loops from configured reaction times scaled by adaptive mode.
+    fn scale_reaction_loops(&mut self) {
+        let scale = |ms: u64| -> u64 {
+            let loops: u64 = self.config.reaction_loops(ms) * self.adaptive_scale as u64 / 100;
+            return std::cmp::max(loops, 1);
+        };
+        self.reaction_loops_raise = scale(self.config.reaction_time_ms_raise);
+        self.reaction_loops_lower = scale(self.config.reaction_time_ms_lower);
+    }
+
+    // Lengthen or shorten reaction times by adaptive step within limits.
+    fn adjust_adaptive_scale(&mut self, lengthen: bool, reason: &str) {
+        let scale: u32 = if lengthen {
+            std::cmp::min(
+                self.adaptive_scale + ADAPTIVE_SCALE_STEP,
+                ADAPTIVE_SCALE_MAX,
+            )
+        } else {
+            std::cmp::max(
+                self.adaptive_scale.saturating_sub(ADAPTIVE_SCALE_STEP),
+                ADAPTIVE_SCALE_MIN,
+            )
+        };
+        if scale == self.adaptive_scale {
+            return;
+        }
+        self.adaptive_scale = scale;
+        self.scale_reaction_loops();
+        log::warn!(
+            "Adaptive: {}, scaled reaction times to {} % (raise {} loops, lower {} loops)",
+            reason,
+            scale,
+            self.reaction_loops_raise,
+            self.reaction_loops_lower
+        );
+    }
+
+    // Observe fan speed change of this loop and tune reaction times from it in adaptive mode.
+    fn adapt(&mut self, fan_speed_before: u8) {
+        self.adaptive_change_loops = self.adaptive_change_loops.saturating_add(1);
+        if self.fan_speed == fan_speed_before {
+            return;
+        }
+        let raised: bool = fan_speed_before < self.fan_speed;
+
+        // Quick reversal of direction means controller reacts too eagerly.
+        if self.adaptive_raised == Some(!raised)
+            && self.adaptive_change_loops <= self.config.reaction_loops(ADAPTIVE_REVERSAL_MS)
+        {
+            self.adjust_adaptive_scale(true, "fan speed oscillates");
+        }
+
+        // Raising again and again means controller falls behind temperature.
+        if raised {
+            self.adaptive_raises += 1;
+            if ADAPTIVE_LAG_RAISES <= self.adaptive_raises {
+                self.adjust_adaptive_scale(false, "fan speed lags temperature");
+                self.adaptive_raises = 0;
+            }
+        } else {
+            self.adaptive_raises = 0;
+        }
+
+        self.adaptive_raised = Some(raised);
+        self.adaptive_change_loops = 0;
+    }
+
+    // Apply reloaded configuration while keeping current fan speed state.
+    pub fn reload(&mut self, config: &Config) {
+        self.config = config.clone();
+        self.scale_reaction_loops();
+        self.pid = Pid::new(config.pid_kp, config.pid_ki, config.pid_kd);
+
+        // Passive mode can not be left anymore if it was turned off.
+        if config.passive_below.is_none() {
+            self.passive = false;
+        }
+        if config.stop_temp.is_none() {
+            self.zone = Zone::Running;
+        }
+        // Keep running fan within new speed limits.
+        if !self.stopped() {
+            self.fan_speed = self.fan_speed.clamp(config.fan_speed_min, self.speed_max());
+        }
+    }
+
+    // Tell controller whether system is idle.
+    pub fn set_idle(&mut self, idle: bool) {
+        if self.idle == idle {
+            return;
+        }
+        self.idle = idle;
+        if idle {
+            log::info!("System is idle, using idle reaction time and ceiling.");
+            // Bring fan under idle ceiling right away.
+            if !self.stopped() {
+                self.fan_speed = std::cmp::min(self.fan_speed, self.speed_max());
+            }
+        } else {
+            log::info!("System is no longer idle.");
+        }
+    }
+
+    // Tell controller whether turbo is on.
+    pub fn set_turbo(&mut self, turbo: bool) {
+        if self.turbo == turbo {
+            return;
+        }
+        self.turbo = turbo;
+        if turbo {
+            log::info!(
+                "Turbo on, lifting fan speed ceiling to {} %.",
+                FAN_SPEED_MAX
+            );
+        } else {
+            log::info!("Turbo off.");
+            // Bring fan back under configured ceiling.
+            if !self.stopped() {
+                self.fan_speed = std::cmp::min(self.fan_speed, self.speed_max());
+            }
+        }
+    }
+
+    // Tell controller latest CPU utilization, from 0 to 1.
+    pub fn set_cpu_utilization(&mut self, utilization: f32) {
+        self.cpu_utilization = utilization.clamp(0.0, 1.0);
+    }
+
+    // Speed that CPU utilization alone asks for, ahead of temperature rising.
+    fn feed_forward_speed(&self) -> u8 {
+        let boost: f32 = (self.config.cpu_weight * self.cpu_utilization).clamp(0.0, 100.0);
+        return std::cmp::min(
+            self.config
+                .fan_speed_min
+                .saturating_add(boost.round() as u8),
+            self.speed_max(),
+        );
+    }
+
+    // Highest fan speed, max in turbo and lower while idle unless temperature is over maximum.
+    fn speed_max(&self) -> u8 {
+        if self.turbo {
+            return FAN_SPEED_MAX;
+        }
+        if self.idle && self.temp_last <= celsius_to_tenths(self.config.max_temp) {
+            return std::cmp::max(
+                std::cmp::min(self.config.idle_fan_speed_max, self.config.fan_speed_max),
+                self.config.fan_speed_min,
+            );
+        }
+        return self.config.fan_speed_max;
+    }
+
+    // Wait this many loops to raise fan speed at given temperature,
+    // over maximum temperature reaction time can be overridden and turbo shortens it,
+    // both of which can raise on first loop.
+    fn raise_reaction_loops(&self, temp: u16) -> u64 {
+        let mut loops: u64 = self.reaction_loops_raise;
+        if celsius_to_tenths(self.config.max_temp) < temp {
+            if let Some(over_max) = self.config.reaction_time_ms_over_max {
+                loops = self.config.delay_loops(over_max);
+            }
+        }
+        if self.turbo {
+            loops = std::cmp::min(self.config.delay_loops(TURBO_REACTION_TIME_MS_RAISE), loops);
+        }
+        return loops;
+    }
+
+    // Wait this many loops to lower fan speed, longer while idle.
+    fn lower_reaction_loops(&self) -> u64 {
+        if self.idle {
+            return self
+                .config
+                .reaction_loops(self.config.idle_reaction_time_ms_lower);
+        }
+        return self.reaction_loops_lower;
+    }
+
+    // Has temperature dropped enough under the one that last raised fan speed to allow lowering?
+    fn lowering_allowed(&self, temp: u16) -> bool {
+        if self.config.hysteresis == 0 {
+            return true;
+        }
+        return match self.raise_temp {
+            Some(raise_temp) => {
+                temp.saturating_add(celsius_to_tenths(self.config.hysteresis)) <= raise_temp
+            }
+            None => true,
+        };
+    }
+
+    // Is temperature rising since last loop faster than fast rise threshold?
+    fn rising_fast(&self, temp: u16) -> bool {
+        let fast_rise: f32 = match self.config.fast_rise {
+            Some(fast_rise) => fast_rise,
+            None => return false,
+        };
+        let dt: f32 = self.config.refresh_rate as f32 / 1000_f32;
+        if dt <= 0.0 {
+            return false;
+        }
+        let rate: f32 = (temp as f32 - self.temp_last as f32) / 10_f32 / dt;
+        if fast_rise < rate {
+            log::info!("Temperature rising fast at {:.1} C/s!", rate);
+            return true;
+        }
+        return false;
+    }
+
+    // Count down cooldown loops and tell if fan speed can be lowered already.
+    fn cooldown_passed(&mut self) -> bool {
+        if self.config.cooldown_ms == 0 {
+            return true;
+        }
+        let remaining: u64 = match self.cooldown_loops {
+            Some(remaining) => remaining,
+            None => {
+                log::info!(
+                    "Cooling down for {} ms before lowering.",
+                    self.config.cooldown_ms
+                );
+                self.config.cooldown_ms / std::cmp::max(self.config.refresh_rate, 1)
+            }
+        };
+        self.cooldown_loops = Some(remaining.saturating_sub(1));
+        return remaining == 0;
+    }
+
+    // Has fan stayed in its current passive state for at least given milliseconds.
+    fn held_for(&self, ms: u64) -> bool {
+        return ms / std::cmp::max(self.config.refresh_rate, 1) <= self.passive_transition_loops;
+    }
+
+    // Enter or leave passive mode depending on temperature.
+    fn update_passive(&mut self, temp: u16) {
+        let passive_below: u16 = match self.config.passive_below {
+            Some(passive_below) => celsius_to_tenths(passive_below),
+            None => return,
+        };
+        self.passive_transition_loops = self.passive_transition_loops.saturating_add(1);
+        if self.passive {
+            // Resume normal control from minimum speed once temperature rises over margin,
+            // after fan has been off long enough unless temperature is over maximum.
+            if passive_below.saturating_add(celsius_to_tenths(self.config.passive_hysteresis))
+                < temp
+                && (self.held_for(self.config.min_off_ms)
+                    || celsius_to_tenths(self.config.max_temp) < temp)
+            {
+                log::info!("Leaving passive mode at {}", format_tenths(temp, false));
+                self.passive = false;
+                self.passive_transition_loops = 0;
+                self.fan_speed = self.config.fan_speed_min;
+            }
+        } else if temp < passive_below && self.held_for(self.config.min_on_ms) {
+            // Turn fan fully off, minimum speed only applies when fan is running.
+            log::info!("Entering passive mode at {}", format_tenths(temp, false));
+            self.passive = true;
+            self.passive_transition_loops = 0;
+            self.fan_speed = 0;
+        }
+    }
+
+    // Is fan stopped by passive mode or semi-passive stop temperature?
+    fn stopped(&self) -> bool {
+        return self.passive || self.zone == Zone::Stopped;
+    }
+
+    // Move between running, idling and stopped zones of semi-passive control.
+    fn update_zone(&mut self, temp: u16) {
+        let (stop_temp, start_temp): (u16, u16) =
+            match (self.config.stop_temp, self.config.start_temp) {
+                (Some(stop_temp), Some(start_temp)) => {
+                    (celsius_to_tenths(stop_temp), celsius_to_tenths(start_temp))
+                }
+                _ => return,
+            };
+        let zone: Zone = match self.zone {
+            // Only rising over start temperature starts stopped fan again, from minimum speed.
+            Zone::Stopped if start_temp < temp => {
+                self.fan_speed = self.config.fan_speed_min;
+                Zone::Running
+            }
+            Zone::Stopped => Zone::Stopped,
+            // Running or idling fan stops under stop temperature.
+            _ if temp < stop_temp => {
+                self.fan_speed = 0;
+                Zone::Stopped
+            }
+            // Running fan drops to minimum speed under start temperature.
+            Zone::Running if temp < start_temp => {
+                self.fan_speed = self.config.fan_speed_min;
+                Zone::Idling
+            }
+            Zone::Idling if start_temp < temp => Zone::Running,
+            zone => zone,
+        };
+        if zone != self.zone {
+            log::info!(
+                "Semi-passive zone {:?} at {}",
+                zone,
+                format_tenths(temp, false)
+            );
+            self.zone = zone;
+        }
+    }
+
+    // Move fan speed by increments depending on if temperature is over limits or raising.
+    fn step_increment(&mut self, temp: u16) {
+        // If temperature is over the max
+        if celsius_to_tenths(self.config.max_temp) < temp
+            // or
+            || (
+                // min temperature has been reached
+                celsius_to_tenths(self.config.min_temp) < temp
+                // and
+                &&
+                // temperature is raising.
+                self.temp_last < temp
+            )
+        {
+            // Output information about it.
+            log::info!("Raising or over max!");
+
+            // Increase raising loops.
+            self.raising_loops += 1;
+
+            // If has been raising more than reaction time gives or is rising fast.
+            if self.raise_reaction_loops(temp) < self.raising_loops || self.rising_fast(temp) {
+                // Raise fan speed by defined increment.
+                self.fan_speed = std::cmp::min(
+                    self.fan_speed
+                        .saturating_add(self.config.fan_raise_increment),
+                    self.speed_max(),
+                );
+                self.raise_temp = Some(temp);
+
+                // Zero loop counters.
+                self.lowering_or_staying_loops = 0;
+                self.raising_loops = 0;
+            }
+        }
+        // If holding is wanted and temperature over minimum stays the same.
+        else if self.config.hold_on_steady
+            && celsius_to_tenths(self.config.min_temp) < temp
+            && self.temp_last == temp
+        {
+            // Keep current fan speed, neither raising nor lowering it.
+            log::info!("Holding steady.");
+        }
+        // Fan speed is not raising or over the max.
+        else {
+            // Inform about it.
+            log::info!("Lowering or staying the same.");
+            // Increase loop counter.
+            self.lowering_or_staying_loops += 1;
+
+            // If has been lowering or staying the same more than reaction time gives
+            // and temperature has dropped out of hysteresis band.
+            if self.lower_reaction_loops() < self.lowering_or_staying_loops
+                && self.lowering_allowed(temp)
+                && self.cooldown_passed()
+            {
+                // Lower fan speed by defined increment.
+                self.fan_speed = std::cmp::max(
+                    self.fan_speed
+                        .saturating_sub(self.config.fan_lower_increment),
+                    self.config.fan_speed_min,
+                );
+
+                // Zero loop counters.
+                self.lowering_or_staying_loops = 0;
+                self.raising_loops = 0;
+            }
+        }
+    }
+
+    // Move fan speed toward target speed given by fan curve.
+    fn step_curve(&mut self, temp: u16, target: u8) {
+        // Keep target within speed limits.
+        let target: u8 = std::cmp::max(
+            std::cmp::min(target, self.speed_max()),
+            self.config.fan_speed_min,
+        );
+
+        // If target is over current speed
+        if self.fan_speed < target {
+            // Increase raising loops.
+            self.raising_loops += 1;
+
+            // If has been under target more than reaction time gives or is rising fast.
+            if self.raise_reaction_loops(temp) < self.raising_loops || self.rising_fast(temp) {
+                // Raise fan speed by defined increment without passing target.
+                self.fan_speed = std::cmp::min(
+                    self.fan_speed
+                        .saturating_add(self.config.fan_raise_increment),
+                    target,
+                );
+                self.raise_temp = Some(temp);
+
+                // Zero loop counters.
+                self.lowering_or_staying_loops = 0;
+                self.raising_loops = 0;
+            }
+        }
+        // Target is under current speed.
+        else if target < self.fan_speed {
+            // Increase loop counter.
+            self.lowering_or_staying_loops += 1;
+
+            // If has been over target more than reaction time gives
+            // and temperature has dropped out of hysteresis band.
+            if self.lower_reaction_loops() < self.lowering_or_staying_loops
+                && self.lowering_allowed(temp)
+                && self.cooldown_passed()
+            {
+                // Lower fan speed by defined increment without passing target.
+                self.fan_speed = std::cmp::max(
+                    self.fan_speed
+                        .saturating_sub(self.config.fan_lower_increment),
+                    target,
+                );
+
+                // Zero loop counters.
+                self.lowering_or_staying_loops = 0;
+                self.raising_loops = 0;
+            }
+        }
+        // Fan speed is at target.
+        else {
+            self.lowering_or_staying_loops = 0;
+            self.raising_loops = 0;
+        }
+    }
+
+    // Set fan speed from PID controller output.
+    fn step_pid(&mut self, temp: u16) {
+        let dt: f32 = self.config.refresh_rate as f32 / 1000_f32;
+        let output: u8 = self
+            .pid
+            .update(self.config.pid_setpoint, temp as f32 / 10_f32, dt);
+        log::info!("PID output {} %", output);
+        self.fan_speed = std::cmp::max(
+            std::cmp::min(output, self.speed_max()),
+            self.config.fan_speed_min,
+        );
+    }
+
+    // Move applied fan speed toward wanted one by at most max step.
+    fn limit_step(&self) -> u8 {
+        let max_step: u8 = match self.config.max_step {
+            Some(max_step) if 0 < max_step && self.fan_speed_last != FAN_SPEED_UNKNOWN => max_step,
+            _ => return self.fan_speed,
+        };
+        let next: u8 = if self.fan_speed_last < self.fan_speed {
+            std::cmp::min(self.fan_speed_last.saturating_add(max_step), self.fan_speed)
+        } else {
+            std::cmp::max(self.fan_speed_last.saturating_sub(max_step), self.fan_speed)
+        };
+
+        // Fan that should be spinning starts from minimum speed.
+        if self.config.fan_speed_min <= self.fan_speed && next < self.config.fan_speed_min {
+            return self.config.fan_speed_min;
+        }
+        return next;
+    }
+
+    // Fan speed controller wants.
+    pub fn fan_speed(&self) -> u8 {
+        return self.fan_speed;
+    }
+
+    // Fan speed when last set, FAN_SPEED_UNKNOWN until first write.
+    pub fn fan_speed_last(&self) -> u8 {
+        return self.fan_speed_last;
+    }
+
+    // How many loops has temperature been raising.
+    pub fn raising_loops(&self) -> u64 {
+        return self.raising_loops;
+    }
+
+    // How many loops has temperature been lowering or staying the same.
+    pub fn lowering_or_staying_loops(&self) -> u64 {
+        return self.lowering_or_staying_loops;
+    }
+
+    // Start from given fan speed without it having been written,
+    // so that first decision writes even if it is same speed.
+    pub fn assume_speed(&mut self, speed: u8) {
+        self.force_speed(speed);
+        self.fan_speed_last = FAN_SPEED_UNKNOWN;
+    }
+
+    // Force fan speed that was set outside of controller.
+    pub fn force_speed(&mut self, speed: u8) {
+        self.fan_speed = speed;
+        self.fan_speed_last = speed;
+        self.lowering_or_staying_loops = 0;
+        self.raising_loops = 0;
+    }
+
+    // Is wanted fan speed far enough from applied one to be written,
+    // stopping fan and speed limits are always applied so they can be reached.
+    fn outside_deadband(&self) -> bool {
+        return self.config.deadband < self.fan_speed.abs_diff(self.fan_speed_last)
+            || self.fan_speed == 0
+            || self.fan_speed == self.config.fan_speed_min
+            || self.fan_speed == self.speed_max();
+    }
+
+    // Advance controller with given temperature, returns new fan speed if it should change.
+    pub fn step(&mut self, temp: u8) -> Option<u8> {
+        return self.step_tenths(celsius_to_tenths(temp));
+    }
+
+    // Set max speed without waiting for reaction time or ramping, leaving passive mode.
+    fn step_critical(&mut self, temp: u16) -> Option<u8> {
+        self.temp_last = temp;
+        self.passive = false;
+        self.zone = Zone::Running;
+        self.raise_temp = Some(temp);
+        self.cooldown_loops = None;
+        self.lowering_or_staying_loops = 0;
+        self.raising_loops = 0;
+        self.keeping_loops = 0;
+        self.fan_speed = FAN_SPEED_MAX;
+        if self.fan_speed_last == FAN_SPEED_MAX {
+            return None;
+        }
+        log::error!(
+            "CRITICAL TEMPERATURE {}, SETTING FAN SPEED TO {} %!",
+            format_tenths(temp, false),
+            FAN_SPEED_MAX
+        );
+        self.fan_speed_last = FAN_SPEED_MAX;
+        return Some(FAN_SPEED_MAX);
+    }
+
+    // Advance controller with given temperature in tenths of degree,
+    // returns new fan speed if it should change.
+    pub fn step_tenths(&mut self, temp: u16) -> Option<u8> {
+        // Over critical temperature run at max speed right away.
+        if celsius_to_tenths(self.config.critical_temp()) <= temp {
+            return self.step_critical(temp);
+        }
+
+        self.update_passive(temp);
+        self.update_zone(temp);
+        // Rising temperature restarts cooldown next time fan speed would be lowered.
+        if self.temp_last < temp {
+            self.cooldown_loops = None;
+        }
+        // Control mode only decides speed of running fan, idling one stays at minimum speed.
+        if !self.passive && self.zone == Zone::Running {
+            let fan_speed_before: u8 = self.fan_speed;
+            match (self.config.mode, &self.config.curve) {
+                (Mode::Pid, _) => self.step_pid(temp),
+                (Mode::Increment, Some(curve)) => {
+                    let target: u8 = curve.speed_for_tenths(temp);
+                    log::info!("Fan curve target {} %", target);
+                    self.step_curve(temp, target);
+                }
+                (Mode::Increment, None) => self.step_increment(temp),
+            }
+
+            // Raise fan ahead of temperature when CPU is busy.
+            if 0.0 < self.config.cpu_weight {
+                let feed_forward: u8 = self.feed_forward_speed();
+                if self.fan_speed < feed_forward {
+                    log::info!(
+                        "CPU utilization {:.0} % raises fan speed to {} %",
+                        self.cpu_utilization * 100.0,
+                        feed_forward
+                    );
+                    self.fan_speed = feed_forward;
+                }
+            }
+
+            // Tune reaction times from how fan speed moved.
+            if self.config.adaptive {
+                self.adapt(fan_speed_before);
+            }
+        }
+
+        // Update last loop temperature from this loop temperature.
+        self.temp_last = temp;
+
+        // If fan speed changed more than dead-band or nothing has been written yet.
+        if self.fan_speed_last == FAN_SPEED_UNKNOWN
+            || (self.fan_speed != self.fan_speed_last && self.outside_deadband())
+        {
+            // Ramp toward wanted speed if change per write is limited.
+            let fan_speed: u8 = self.limit_step();
+
+            // Output about the change.
+            if self.fan_speed_last == FAN_SPEED_UNKNOWN {
+                log::info!("Setting fan speed to {} %", fan_speed);
+            } else if fan_speed == self.fan_speed {
+                log::info!(
+                    "Changing fan speed {} % => {} %",
+                    self.fan_speed_last,
+                    fan_speed
+                );
+            } else {
+                log::info!(
+                    "Changing fan speed {} % => {} % toward {} %",
+                    self.fan_speed_last,
+                    fan_speed,
+                    self.fan_speed
+                );
+            }
+
+            // Zero loop counters.
+            self.lowering_or_staying_loops = 0;
+            self.raising_loops = 0;
+            self.keeping_loops = 0;
+
+            // Save fan speed as last fan speed.
+            self.fan_speed_last = fan_speed;
+
+            return Some(fan_speed);
+        }
+
+        // Fan speed did not change, log it when it first stays the same and then as heartbeat.
+        let keeping_log_loops: u64 = std::cmp::max(self.config.reaction_loops(KEEPING_LOG_MS), 1);
+        if self.keeping_loops % keeping_log_loops == 0 {
+            log::info!("Keeping fan speed at {} %", self.fan_speed);
+        }
+        self.keeping_loops = self.keeping_loops.saturating_add(1);
+        return None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::temperature::{MockTemperature, TemperatureSource};
+
+    // Controller at given temperature whose minimum speed has already been written.
+    fn new_controller(config: &Config, temp: u8) -> FanController {
+        let mut controller: FanController = FanController::new(config, temp);
+        controller.force_speed(config.fan_speed_min);
+        return controller;
+    }
+
+    // Run increment step over maximum temperature until fan speed is raised once.
+    fn raise_once(controller: &mut FanController) {
+        let temp: u16 = celsius_to_tenths(controller.config.max_temp + 3);
+        for _ in 0..=controller.reaction_loops_raise {
+            controller.step_increment(temp);
+        }
+    }
+
+    // Run increment step under minimum temperature until fan speed is lowered once.
+    fn lower_once(controller: &mut FanController) {
+        let temp: u16 = celsius_to_tenths(controller.config.min_temp - 10);
+        controller.temp_last = temp;
+        for _ in 0..=controller.reaction_loops_lower {
+            controller.step_increment(temp);
+        }
+    }
+
+    // Zero increments never move fan speed.
+    #[test]
+    fn zero_increment_keeps_speed() {
+        let config: Config = Config {
+            fan_raise_increment: 0,
+            fan_lower_increment: 0,
+            ..Config::default()
+        };
+        let mut controller: FanController = new_controller(&config, 60);
+        controller.force_speed(50);
+        for _ in 0..5 {
+            raise_once(&mut controller);
+        }
+        assert_eq!(controller.fan_speed(), 50);
+        for _ in 0..5 {
+            lower_once(&mut controller);
+        }
+        assert_eq!(controller.fan_speed(), 50);
+    }
+
+    // Increments larger than whole speed range saturate at limits instead of wrapping.
+    #[test]
+    fn increment_over_range_saturates() {
+        let config: Config = Config {
+            fan_raise_increment: u8::MAX,
+            fan_lower_increment: u8::MAX,
+            ..Config::default()
+        };
+        let mut controller: FanController = new_controller(&config, 60);
+        controller.force_speed(50);
+        raise_once(&mut controller);
+        assert_eq!(controller.fan_speed(), config.fan_speed_max);
+        raise_once(&mut controller);
+        assert_eq!(controller.fan_speed(), config.fan_speed_max);
+        lower_once(&mut controller);
+        assert_eq!(controller.fan_speed(), config.fan_speed_min);
+        lower_once(&mut controller);
+        assert_eq!(controller.fan_speed(), config.fan_speed_min);
+    }
+
+    // Equal minimum and maximum speed pins fan speed.
+    #[test]
+    fn equal_speed_limits_pin_speed() {
+        let config: Config = Config {
+            fan_speed_min: 60,
+            fan_speed_max: 60,
+            ..Config::default()
+        };
+        let mut controller: FanController = new_controller(&config, 60);
+        raise_once(&mut controller);
+        assert_eq!(controller.fan_speed(), 60);
+        lower_once(&mut controller);
+        assert_eq!(controller.fan_speed(), 60);
+    }
+
+    // Step limit moves toward wanted speed without overshooting, whatever its size.
+    #[test]
+    fn limit_step_boundaries() {
+        let mut controller: FanController = new_controller(&Config::default(), 60);
+        controller.fan_speed_last = 30;
+        controller.fan_speed = 100;
+        for (max_step, expected) in [
+            (None, 100),
+            (Some(0), 100),
+            (Some(10), 40),
+            (Some(u8::MAX), 100),
+        ] {
+            controller.config.max_step = max_step;
+            assert_eq!(controller.limit_step(), expected, "max step {:?}", max_step);
+        }
+
+        // Lowering with huge step stops at wanted speed, and stopping fan is allowed.
+        controller.config.max_step = Some(u8::MAX);
+        controller.fan_speed_last = 100;
+        controller.fan_speed = 30;
+        assert_eq!(controller.limit_step(), 30);
+        controller.fan_speed = 0;
+        assert_eq!(controller.limit_step(), 0);
+
+        // Stopped fan starts from minimum speed even when step is smaller.
+        controller.config.max_step = Some(10);
+        controller.fan_speed_last = 0;
+        controller.fan_speed = 100;
+        assert_eq!(controller.limit_step(), controller.config.fan_speed_min);
+    }
+
+    // Idle, then sudden spike to 90 C, then cooldown back to idle, one temperature per loop.
+    fn ramp_profile() -> Vec<u8> {
+        let mut temps: Vec<u8> = vec![50; 40];
+        temps.extend(vec![90; 60]);
+        temps.extend((50..90).rev());
+        temps.extend(vec![50; 600]);
+        return temps;
+    }
+
+    // Full temperature ramp raises fan speed and then lowers it, within limits and reaction times.
+    #[test]
+    fn temperature_ramp_gives_sane_speed_curve() {
+        let config: Config = Config {
+            fan_speed_max: 80,
+            // Spike must go through normal raising instead of critical override.
+            critical_temp: Some(95),
+            ..Config::default()
+        };
+        let profile: Vec<u8> = ramp_profile();
+        let mut source: MockTemperature = MockTemperature::new(profile.clone());
+        let mut sink: RecordingFan = RecordingFan::default();
+        let mut controller: FanController = new_controller(&config, profile[0]);
+
+        // Loop index of every written speed.
+        let mut write_loops: Vec<usize> = Vec::new();
+        for index in 0..profile.len() {
+            let temp: u8 = source.read().unwrap();
+            if let Some(speed) = controller.step(temp) {
+                sink.set(speed).unwrap();
+                write_loops.push(index);
+            }
+        }
+        let speeds: &Vec<u8> = &sink.speeds;
+
+        // Speed rose to its peak and then fell back to minimum.
+        let peak: usize = speeds
+            .iter()
+            .enumerate()
+            .max_by_key(|(index, speed)| (**speed, std::cmp::Reverse(*index)))
+            .map(|(index, _)| index)
+            .unwrap();
+        assert!(config.fan_speed_min < speeds[peak]);
+        assert!(speeds[..=peak].windows(2).all(|pair| pair[0] < pair[1]));
+        assert!(speeds[peak..].windows(2).all(|pair| pair[1] < pair[0]));
+        assert_eq!(*speeds.last().unwrap(), config.fan_speed_min);
+        assert!(speeds.iter().all(|speed| *speed <= config.fan_speed_max));
+        assert_eq!(speeds[peak], config.fan_speed_max);
+
+        // Changes waited configured number of loops since previous one.
+        let raise_loops: usize = config.reaction_loops(config.reaction_time_ms_raise) as usize;
+        let lower_loops: usize = config.reaction_loops(config.reaction_time_ms_lower) as usize;
+        for (index, pair) in write_loops.windows(2).enumerate() {
+            let waited: usize = pair[1] - pair[0];
+            if index < peak {
+                assert!(raise_loops < waited, "raised after {} loops", waited);
+            } else {
+                assert!(lower_loops < waited, "lowered after {} loops", waited);
+            }
+        }
+    }
+
+    // Packing speed into mock register keeps its other bits and unpacks back to same speed.
+    #[test]
+    fn pack_speed_keeps_other_bits() {
+        for (mask, shift) in [(0x0F, 0), (0xF0, 4), (0x3C, 2), (0xFF, 0)] {
+            let mut register: u8 = 0b1010_0101;
+            let others: u8 = register & !mask;
+            let field_max: u16 = (mask >> shift) as u16;
+            for raw in 0..=u8::MAX {
+                register = pack_speed(register, raw, u8::MAX, mask, shift);
+                assert_eq!(register & !mask, others, "mask {:#04x} raw {}", mask, raw);
+
+                // Unpacked speed is within one field step of written one.
+                let unpacked: u8 = unpack_speed(register, u8::MAX, mask, shift);
+                let step: u16 = u8::MAX as u16 / field_max;
+                assert!(
+                    unpacked.abs_diff(raw) as u16 <= step,
+                    "mask {:#04x} raw {}",
+                    mask,
+                    raw
+                );
+            }
+        }
+
+        // Full and zero speed map to all and none of masked bits.
+        assert_eq!(pack_speed(0x00, 255, 255, 0xF0, 4), 0xF0);
+        assert_eq!(pack_speed(0xFF, 0, 255, 0xF0, 4), 0x0F);
+        assert_eq!(unpack_speed(0xF3, 255, 0xF0, 4), 255);
+        assert_eq!(unpack_speed(0x0F, 255, 0xF0, 4), 0);
+        // Speed range of other firmware scales to field.
+        assert_eq!(pack_speed(0x00, 100, 100, 0x0F, 0), 0x0F);
+        assert_eq!(unpack_speed(0x0F, 100, 0x0F, 0), 100);
+    }
+
+    // Count loops at given temperature until fan speed changes, none if it does not within limit.
+    fn loops_until_change(controller: &mut FanController, temp: u8) -> Option<u64> {
+        return (1..=100).find(|_| controller.step(temp).is_some());
+    }
+
+    // Rising temperature under maximum raises after raise reaction time.
+    #[test]
+    fn normal_rise_waits_raise_reaction_time() {
+        let config: Config = Config::default();
+        let mut controller: FanController = new_controller(&config, 71);
+        let mut loops: u64 = 0;
+        for temp in 72..90 {
+            loops += 1;
+            if controller.step(temp).is_some() {
+                break;
+            }
+        }
+        assert_eq!(
+            loops,
+            config.reaction_loops(config.reaction_time_ms_raise) + 1
+        );
+    }
+
+    // Temperature staying under minimum lowers after lower reaction time.
+    #[test]
+    fn normal_fall_waits_lower_reaction_time() {
+        let config: Config = Config::default();
+        let mut controller: FanController = new_controller(&config, 60);
+        controller.force_speed(50);
+        assert_eq!(
+            loops_until_change(&mut controller, 60),
+            Some(config.reaction_loops(config.reaction_time_ms_lower) + 1)
+        );
+    }
+
+    // Over maximum temperature uses raise reaction time unless overridden.
+    #[test]
+    fn over_max_waits_raise_reaction_time_by_default() {
+        let config: Config = Config::default();
+        let mut controller: FanController = new_controller(&config, 86);
+        assert_eq!(
+            loops_until_change(&mut controller, 86),
+            Some(config.reaction_loops(config.reaction_time_ms_raise) + 1)
+        );
+    }
+
+    // Zero over maximum reaction time raises on first loop over maximum.
+    #[test]
+    fn over_max_zero_delay_raises_right_away() {
+        let config: Config = Config {
+            reaction_time_ms_over_max: Some(0),
+            ..Config::default()
+        };
+        let mut controller: FanController = new_controller(&config, 80);
+        assert_eq!(loops_until_change(&mut controller, 86), Some(1));
+        assert_eq!(loops_until_change(&mut controller, 86), Some(1));
+
+        // Under maximum normal raise reaction time applies again.
+        let under_max: FanController = new_controller(&config, 71);
+        assert_eq!(
+            under_max.raise_reaction_loops(celsius_to_tenths(72)),
+            config.reaction_loops(config.reaction_time_ms_raise)
+        );
+    }
+
+    // Over maximum reaction time raises on loop that reaches it.
+    #[test]
+    fn over_max_delay_raises_at_given_time() {
+        let config: Config = Config {
+            reaction_time_ms_over_max: Some(500),
+            ..Config::default()
+        };
+        let mut controller: FanController = new_controller(&config, 86);
+        assert_eq!(loops_until_change(&mut controller, 86), Some(2));
+    }
+
+    // Turbo raises once its reaction time of one refresh has passed.
+    #[test]
+    fn turbo_raises_on_first_loop() {
+        let config: Config = Config::default();
+        let mut controller: FanController = new_controller(&config, 86);
+        controller.set_turbo(true);
+        assert_eq!(loops_until_change(&mut controller, 86), Some(1));
+    }
+
+    // Steady warm temperature holds fan speed, and lowering resumes once temperature drops.
+    #[test]
+    fn hold_on_steady_keeps_speed() {
+        let config: Config = Config {
+            hold_on_steady: true,
+            ..Config::default()
+        };
+        let mut controller: FanController = new_controller(&config, 75);
+        controller.force_speed(50);
+        assert_eq!(loops_until_change(&mut controller, 75), None);
+        assert_eq!(controller.fan_speed(), 50);
+
+        // Dropping temperature lowers after lower reaction time.
+        let lower_loops: u64 = config.reaction_loops(config.reaction_time_ms_lower);
+        let mut loops: u64 = 0;
+        for temp in (60..75).rev() {
+            loops += 1;
+            if controller.step(temp).is_some() {
+                break;
+            }
+        }
+        assert_eq!(loops, lower_loops + 1);
+        assert!(controller.fan_speed() < 50);
+    }
+
+    // Steady temperature does not block raising when temperature rises again.
+    #[test]
+    fn hold_on_steady_raises_on_rise() {
+        let config: Config = Config {
+            hold_on_steady: true,
+            ..Config::default()
+        };
+        let mut controller: FanController = new_controller(&config, 75);
+        controller.force_speed(50);
+        assert_eq!(loops_until_change(&mut controller, 75), None);
+        let mut loops: u64 = 0;
+        for temp in 76..89 {
+            loops += 1;
+            if controller.step(temp).is_some() {
+                break;
+            }
+        }
+        assert_eq!(
+            loops,
+            config.reaction_loops(config.reaction_time_ms_raise) + 1
+        );
+        assert!(50 < controller.fan_speed());
+    }
+
+    // Without holding, steady temperature counts as lowering.
+    #[test]
+    fn steady_temperature_lowers_without_hold() {
+        let config: Config = Config::default();
+        let mut controller: FanController = new_controller(&config, 75);
+        controller.force_speed(50);
+        assert_eq!(
+            loops_until_change(&mut controller, 75),
+            Some(config.reaction_loops(config.reaction_time_ms_lower) + 1)
+        );
+    }
+
+    // Every percentage survives conversion to raw value and back.
+    #[test]
+    fn percent_raw_round_trip() {
+        for max_raw in [255, 100, 200] {
+            for percent in 0..=100 {
+                let raw: u8 = percent_to_raw(percent, max_raw);
+                assert_eq!(raw_to_percent(raw, max_raw), percent, "max raw {}", max_raw);
+            }
+        }
+        assert_eq!(percent_to_raw(30, 255), 76);
+        assert_eq!(percent_to_raw(150, 255), 255);
+        assert_eq!(raw_to_percent(255, 100), 100);
+    }
+
+    // Temperature staying the same after a rise must compare against last loop, not last speed change.
+    #[test]
+    fn temp_last_follows_every_loop() {
+        let mut controller: FanController = new_controller(&Config::default(), 75);
+        controller.step(76);
+        assert_eq!(controller.raising_loops, 1);
+        assert_eq!(controller.lowering_or_staying_loops, 0);
+
+        controller.step(76);
+        assert_eq!(controller.raising_loops, 1);
+        assert_eq!(controller.lowering_or_staying_loops, 1);
+
+        controller.step(77);
+        assert_eq!(controller.raising_loops, 2);
+    }
+}
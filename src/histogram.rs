@@ -0,0 +1,67 @@
+// How many degrees wide each temperature bucket is.
+pub const HISTOGRAM_BUCKET_WIDTH: u8 = 10;
+
+// Time spent in temperature bucket.
+#[derive(Debug, Clone, Default)]
+struct Bucket {
+    // How long temperature has been in bucket.
+    time: std::time::Duration,
+    // Fan speed percentages multiplied by seconds spent at them.
+    speed_seconds: f64,
+}
+
+// Histogram of how long temperatures were in each bucket and at what fan speed.
+#[derive(Debug, Default)]
+pub struct Histogram {
+    // Buckets by lowest temperature of bucket.
+    buckets: std::collections::BTreeMap<u8, Bucket>,
+    // When each fan was last recorded.
+    last_recorded: std::collections::BTreeMap<u8, std::time::Instant>,
+}
+impl Histogram {
+    // Record temperature and fan speed of fan for time since fan was last recorded.
+    pub fn record(&mut self, fan_id: u8, temp: u8, speed: u8) {
+        let now: std::time::Instant = std::time::Instant::now();
+        let elapsed: std::time::Duration = match self.last_recorded.insert(fan_id, now) {
+            Some(last) => now.duration_since(last),
+            None => return,
+        };
+        let bucket: &mut Bucket = self
+            .buckets
+            .entry(temp - temp % HISTOGRAM_BUCKET_WIDTH)
+            .or_default();
+        bucket.time += elapsed;
+        bucket.speed_seconds += speed as f64 * elapsed.as_secs_f64();
+    }
+
+    // Render histogram as table of time and average fan speed in each temperature bucket.
+    pub fn render(&self) -> String {
+        let mut text: String = format!(
+            "{:<12} {:>12} {:>8} {:>10}\n",
+            "Temperature", "Time (s)", "Share", "Avg speed"
+        );
+        let total: f64 = self
+            .buckets
+            .values()
+            .map(|bucket| bucket.time.as_secs_f64())
+            .sum();
+        for (start, bucket) in &self.buckets {
+            let seconds: f64 = bucket.time.as_secs_f64();
+            if seconds <= 0.0 {
+                continue;
+            }
+            text.push_str(&format!(
+                "{:<12} {:>12.1} {:>7.1}% {:>9.1}%\n",
+                format!(
+                    "{}-{} C",
+                    start,
+                    start.saturating_add(HISTOGRAM_BUCKET_WIDTH - 1)
+                ),
+                seconds,
+                seconds / total * 100.0,
+                bucket.speed_seconds / seconds
+            ));
+        }
+        return text;
+    }
+}
@@ -0,0 +1,110 @@
+// How many points to batch into one write.
+pub const INFLUX_BATCH_POINTS: usize = 10;
+// How many milliseconds to wait at most before writing incomplete batch.
+pub const INFLUX_FLUSH_MS: u64 = 5000;
+// How many points can wait to be written before new ones are dropped.
+pub const INFLUX_QUEUE_CAPACITY: usize = 100;
+// How many seconds to wait for InfluxDB to respond.
+pub const INFLUX_TIMEOUT_S: u64 = 2;
+
+// Writes temperatures and fan speeds to InfluxDB as line protocol.
+pub struct InfluxWriter {
+    sender: std::sync::mpsc::SyncSender<String>,
+    // Host tag of points.
+    host: String,
+    // Are multiple fans written, so that points need fan tags.
+    multiple_fans: bool,
+}
+impl InfluxWriter {
+    // Start writing batches of points to given write URL on background thread,
+    // for example http://localhost:8086/write?db=fans.
+    pub fn start(url: &str, multiple_fans: bool) -> InfluxWriter {
+        let (sender, receiver): (
+            std::sync::mpsc::SyncSender<String>,
+            std::sync::mpsc::Receiver<String>,
+        ) = std::sync::mpsc::sync_channel(INFLUX_QUEUE_CAPACITY);
+        let thread_url: String = url.to_string();
+        std::thread::spawn(move || {
+            let mut batch: Vec<String> = Vec::with_capacity(INFLUX_BATCH_POINTS);
+            loop {
+                let timed_out: bool = match receiver
+                    .recv_timeout(std::time::Duration::from_millis(INFLUX_FLUSH_MS))
+                {
+                    Ok(line) => {
+                        batch.push(line);
+                        false
+                    }
+                    Err(std::sync::mpsc::RecvTimeoutError::Timeout) => true,
+                    // Controller stopped.
+                    Err(std::sync::mpsc::RecvTimeoutError::Disconnected) => return,
+                };
+                // Write full batch, or incomplete one when no points have come for a while.
+                let full: bool = INFLUX_BATCH_POINTS <= batch.len();
+                let idle: bool = timed_out && !batch.is_empty();
+                if !full && !idle {
+                    continue;
+                }
+                write_batch(&thread_url, &batch);
+                batch.clear();
+            }
+        });
+        log::info!("Writing to InfluxDB at {}", url);
+        return InfluxWriter {
+            sender,
+            host: hostname(),
+            multiple_fans,
+        };
+    }
+
+    // Queue point of fan without blocking, dropping it if queue is full.
+    pub fn write(&self, fan_id: u8, temp: u8, speed: u8) {
+        let timestamp_ns: u128 =
+            match std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH) {
+                Ok(duration) => duration.as_nanos(),
+                Err(_) => 0,
+            };
+        let fan_tag: String = if self.multiple_fans {
+            format!(",fan={}", fan_id)
+        } else {
+            String::new()
+        };
+        let line: String = format!(
+            "fan,host={}{} temp={},speed={} {}",
+            self.host, fan_tag, temp, speed, timestamp_ns
+        );
+        if let Err(error) = self.sender.try_send(line) {
+            log::debug!("Dropped InfluxDB point: {}", error);
+        }
+    }
+}
+
+// Write batch of points, only logging errors so InfluxDB being down can not stop fan control.
+fn write_batch(url: &str, batch: &[String]) {
+    match minreq::post(url)
+        .with_timeout(INFLUX_TIMEOUT_S)
+        .with_body(batch.join("\n"))
+        .send()
+    {
+        Ok(response) if (200..300).contains(&response.status_code) => {}
+        Ok(response) => log::warn!(
+            "InfluxDB write failed with status {}: {}",
+            response.status_code,
+            response.as_str().unwrap_or_default()
+        ),
+        Err(error) => log::warn!("InfluxDB write failed: {}", error),
+    }
+}
+
+// Name of this host with line protocol special characters escaped.
+fn hostname() -> String {
+    let host: String = std::fs::read_to_string("/proc/sys/kernel/hostname")
+        .map(|host| host.trim().to_string())
+        .unwrap_or_default();
+    if host.is_empty() {
+        return "localhost".to_string();
+    }
+    return host
+        .replace(',', "\\,")
+        .replace('=', "\\=")
+        .replace(' ', "\\ ");
+}
@@ -0,0 +1,29 @@
+// Fan controller for Clevo NS50MU.
+//
+// Embedded controller protocol, fan control algorithm and temperature sources
+// used by the ns50mu_fan_controller_rs binary. Build without default features
+// to use them from another program without the command line dependencies.
+
+pub mod config;
+pub mod csv_log;
+#[cfg(feature = "dbus")]
+pub mod dbus;
+pub mod ec;
+pub mod error;
+pub mod fan;
+pub mod histogram;
+#[cfg(feature = "influx")]
+pub mod influx;
+pub mod load;
+pub mod log_file;
+pub mod loop_watchdog;
+pub mod metrics;
+#[cfg(feature = "bin")]
+pub mod monitor;
+#[cfg(feature = "mqtt")]
+pub mod mqtt;
+pub mod pid_file;
+pub mod power;
+pub mod status;
+pub mod temperature;
+pub mod trace;
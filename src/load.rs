@@ -0,0 +1,53 @@
+// File that kernel reports load averages in.
+pub const LOADAVG_PATH: &str = "/proc/loadavg";
+
+// Read 1 minute load average, none if it can not be read.
+pub fn load_average() -> Option<f32> {
+    let content: String = std::fs::read_to_string(LOADAVG_PATH).ok()?;
+    return content.split_whitespace().next()?.parse::<f32>().ok();
+}
+
+// File that kernel reports CPU time counters in.
+pub const STAT_PATH: &str = "/proc/stat";
+
+// CPU utilization measured from change of CPU time counters between samples.
+#[derive(Debug, Default)]
+pub struct CpuUsage {
+    // Idle and total CPU time of last sample.
+    last: Option<(u64, u64)>,
+}
+impl CpuUsage {
+    pub fn new() -> CpuUsage {
+        CpuUsage { last: None }
+    }
+
+    // Read CPU time counters and return share of time CPU was busy since last sample, from 0 to 1.
+    // None on first sample or when counters can not be read.
+    pub fn sample(&mut self) -> Option<f32> {
+        let (idle, total): (u64, u64) = read_cpu_times()?;
+        let last: Option<(u64, u64)> = self.last.replace((idle, total));
+        let (idle_last, total_last): (u64, u64) = last?;
+        let total_delta: u64 = total.saturating_sub(total_last);
+        if total_delta == 0 {
+            return None;
+        }
+        let idle_delta: u64 = idle.saturating_sub(idle_last);
+        return Some(1.0 - (idle_delta as f32 / total_delta as f32).clamp(0.0, 1.0));
+    }
+}
+
+// Read idle and total CPU time from first line of stat file, idle includes waiting for IO.
+fn read_cpu_times() -> Option<(u64, u64)> {
+    let content: String = std::fs::read_to_string(STAT_PATH).ok()?;
+    let line: &str = content.lines().next()?;
+    let times: Vec<u64> = line
+        .split_whitespace()
+        .skip(1)
+        .map(|field| field.parse::<u64>())
+        .collect::<Result<Vec<u64>, std::num::ParseIntError>>()
+        .ok()?;
+    if times.len() < 5 {
+        return None;
+    }
+    return Some((times[3] + times[4], times.iter().sum()));
+}
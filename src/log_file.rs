@@ -0,0 +1,56 @@
+// Default size in bytes that log file can grow to before it is rotated.
+pub const LOG_MAX_BYTES_DEFAULT: u64 = 10 * 1024 * 1024;
+
+// Log file that is renamed with .1 suffix and started fresh when it grows over maximum size.
+#[derive(Debug)]
+pub struct RotatingLogFile {
+    path: std::path::PathBuf,
+    file: std::fs::File,
+    max_bytes: u64,
+    // How many bytes current file has.
+    written: u64,
+}
+impl RotatingLogFile {
+    // Open log file for appending.
+    pub fn open(path: &std::path::Path, max_bytes: u64) -> Result<RotatingLogFile, std::io::Error> {
+        let file: std::fs::File = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)?;
+        let written: u64 = file.metadata()?.len();
+        return Ok(RotatingLogFile {
+            path: path.to_path_buf(),
+            file,
+            max_bytes,
+            written,
+        });
+    }
+
+    // Rename current file with .1 suffix, replacing older one, and start fresh file.
+    fn rotate(&mut self) -> Result<(), std::io::Error> {
+        let mut rotated: std::ffi::OsString = self.path.clone().into_os_string();
+        rotated.push(".1");
+        std::fs::rename(&self.path, &rotated)?;
+        self.file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        self.written = 0;
+        return Ok(());
+    }
+}
+impl std::io::Write for RotatingLogFile {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, std::io::Error> {
+        // Rotate before line that would grow file over maximum, but never leave file empty.
+        if 0 < self.written && self.max_bytes < self.written + buf.len() as u64 {
+            self.rotate()?;
+        }
+        let written: usize = self.file.write(buf)?;
+        self.written += written as u64;
+        return Ok(written);
+    }
+
+    fn flush(&mut self) -> Result<(), std::io::Error> {
+        return self.file.flush();
+    }
+}
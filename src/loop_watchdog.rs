@@ -0,0 +1,106 @@
+use crate::config::FAN_SPEED_MAX;
+use crate::ec::Ec;
+use crate::error::FanSpeedSetError;
+
+// How many refresh rates loop can take before fans are forced to max speed by default.
+pub const STALL_LOOPS: u64 = 20;
+
+// Tracks when control loop last completed, so stalled loop can be noticed from another thread.
+#[derive(Debug)]
+pub struct LoopWatchdog {
+    // When last loop completed.
+    last_loop: std::sync::Mutex<std::time::Instant>,
+    // How many milliseconds loop can take before it is considered stalled.
+    timeout_ms: std::sync::atomic::AtomicU64,
+    // Were fans forced to max speed since controller last checked.
+    forced: std::sync::atomic::AtomicBool,
+}
+impl LoopWatchdog {
+    pub fn new(timeout_ms: u64) -> LoopWatchdog {
+        LoopWatchdog {
+            last_loop: std::sync::Mutex::new(std::time::Instant::now()),
+            timeout_ms: std::sync::atomic::AtomicU64::new(timeout_ms),
+            forced: std::sync::atomic::AtomicBool::new(false),
+        }
+    }
+
+    // Mark loop completed.
+    pub fn feed(&self) {
+        if let Ok(mut last_loop) = self.last_loop.lock() {
+            *last_loop = std::time::Instant::now();
+        }
+    }
+
+    // Change how long loop can take, when refresh rate is reloaded.
+    pub fn set_timeout_ms(&self, timeout_ms: u64) {
+        self.timeout_ms
+            .store(timeout_ms, std::sync::atomic::Ordering::SeqCst);
+    }
+
+    // Tell if fans were forced to max speed since last call.
+    pub fn take_forced(&self) -> bool {
+        return self.forced.swap(false, std::sync::atomic::Ordering::SeqCst);
+    }
+
+    // How long since last loop completed.
+    fn since_last_loop(&self) -> std::time::Duration {
+        return match self.last_loop.lock() {
+            Ok(last_loop) => last_loop.elapsed(),
+            Err(_) => std::time::Duration::ZERO,
+        };
+    }
+}
+
+// Check loop on background thread and run given fans at max speed when it stalls.
+pub fn start_loop_watchdog(
+    watchdog: std::sync::Arc<LoopWatchdog>,
+    ec: Ec,
+    fan_ids: Vec<u8>,
+    dry_run: bool,
+) {
+    std::thread::spawn(move || {
+        // IO permissions are per thread, so set them for this one.
+        let ec_ready: bool = dry_run || ec.init().is_ok();
+        // Only force once per stall.
+        let mut stalled: bool = false;
+        loop {
+            let timeout: std::time::Duration = std::time::Duration::from_millis(
+                watchdog
+                    .timeout_ms
+                    .load(std::sync::atomic::Ordering::SeqCst),
+            );
+            let since: std::time::Duration = watchdog.since_last_loop();
+            if timeout < since {
+                if !stalled {
+                    stalled = true;
+                    log::error!(
+                        "Control loop has not completed in {:?}, running fans at max speed!",
+                        since
+                    );
+                    if !dry_run && ec_ready {
+                        for fan_id in &fan_ids {
+                            // Stalled loop may be in middle of command sequence, so wait for it to end.
+                            if let Err(error) = ec.set_fan_speed_waiting(*fan_id, FAN_SPEED_MAX) {
+                                log::error!("Failed to set fan {} to max speed: {}", fan_id, error);
+                                // Try again on next check if lock did not free up.
+                                if matches!(error, FanSpeedSetError::EcLockTimedOutError(_)) {
+                                    stalled = false;
+                                }
+                            }
+                        }
+                    }
+                    watchdog
+                        .forced
+                        .store(true, std::sync::atomic::Ordering::SeqCst);
+                }
+            } else {
+                stalled = false;
+            }
+            // Check a few times per timeout.
+            std::thread::sleep(std::cmp::max(
+                timeout / 4,
+                std::time::Duration::from_millis(10),
+            ));
+        }
+    });
+}
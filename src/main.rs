@@ -1,384 +1,2272 @@
-// Embedded controller command port.
-const EC_COMMAND_PORT: u16 = 0x66;
-// Embedded controller data port.
-const EC_DATA_PORT: u16 = 0x62;
+use ns50mu_fan_controller_rs::config::{
+    Config, Mode, Profile, CAPTURE_DWELL_MS, CAPTURE_POLL_MS, CAPTURE_STEP, CONFIG_PATH_DEFAULT,
+    DIAGNOSE_SPEED, DIAGNOSE_TEMP_MAX, FAN_SPEED_MAX, FAN_SPEED_MIN, PREVIEW_SETTLE_MS,
+    PREVIEW_TEMP_MAX, PREVIEW_TEMP_MIN, RESUME_GAP_MS, SHUTDOWN_RAMP_MS, SHUTDOWN_RAMP_STEPS,
+    SPINUP_MS, SPIN_TEST_MS,
+};
+use ns50mu_fan_controller_rs::csv_log::CsvLog;
+#[cfg(feature = "dbus")]
+use ns50mu_fan_controller_rs::dbus::DbusService;
+use ns50mu_fan_controller_rs::ec::{
+    take_flag_wait_timings, Ec, FlagWaitTimings, COMMAND_FLAG_MAX_WAIT_MS, EC_COMMAND_PORT,
+    EC_DATA_PORT, FAN_ID, INIT_BACKOFF_MS, INIT_MAX_BACKOFF_MS, INIT_RETRIES, TEMP_INVALID,
+};
+use ns50mu_fan_controller_rs::error::{
+    CommandFlagWaitTimedOutError, ConfigError, DiagnosticsFailedError, FailedToCaptureError,
+    FailedToSetIOPermissionError, InvalidTemperatureReadingError, RunError, TemperatureReadError,
+};
+use ns50mu_fan_controller_rs::fan::{
+    percent_to_raw, DryRunFan, EcFan, FanController, FanSpeedSink, FAN_SPEED_UNKNOWN,
+};
+use ns50mu_fan_controller_rs::histogram::Histogram;
+#[cfg(feature = "influx")]
+use ns50mu_fan_controller_rs::influx::InfluxWriter;
+use ns50mu_fan_controller_rs::load::{load_average, CpuUsage};
+use ns50mu_fan_controller_rs::log_file::{RotatingLogFile, LOG_MAX_BYTES_DEFAULT};
+use ns50mu_fan_controller_rs::loop_watchdog::{start_loop_watchdog, LoopWatchdog, STALL_LOOPS};
+use ns50mu_fan_controller_rs::metrics::{start_metrics_server, Metrics};
+use ns50mu_fan_controller_rs::monitor::{Monitor, MonitorRow};
+#[cfg(feature = "mqtt")]
+use ns50mu_fan_controller_rs::mqtt::MqttPublisher;
+use ns50mu_fan_controller_rs::pid_file::{acquire_pid_file, remove_pid_file};
+use ns50mu_fan_controller_rs::power::PowerMonitor;
+use ns50mu_fan_controller_rs::status::{start_status_socket, FanStatus, Status};
+use ns50mu_fan_controller_rs::temperature::{
+    celsius_to_tenths, find_thermal_zone, format_temp, format_tenths, list_hwmon_inputs,
+    tenths_to_celsius, Aggregate, Aggregator, EcTemperature, FakeTemperature, HwmonInput,
+    SysfsTemperature, TempSmoother, TemperatureSource, ThermalZoneTemperature, HWMON_PATH_DEFAULT,
+    THERMAL_ZONE_TYPE_CPU,
+};
+use ns50mu_fan_controller_rs::trace::{read_trace, TraceSample};
 
-// Command to get temperature.
-const COMMAND_TEMP: u8 = 0x9E;
-// Command to set speed.
-const COMMAND_SPEED: u8 = 0x99;
+// Command line arguments.
+#[derive(Debug, clap::Parser)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
 
-// Id for fan.
-const FAN_ID: u8 = 0x01;
+    #[command(flatten)]
+    verbose: clap_verbosity_flag::Verbosity,
 
-// Min speed as percentage to run the fan.
-const FAN_SPEED_MIN: u8 = 30;
-// Max speed as percentage to run the fan.
-const FAN_SPEED_MAX: u8 = 100;
+    /// Format of log lines.
+    #[arg(long, value_enum, default_value_t = LogFormat::Text)]
+    log_format: LogFormat,
 
-// Minimum temperature from start to raise fan speed.
-const MIN_TEMP: u8 = 70;
-// Maximun temperature that starts raising fan speed even if temperature is not raising.
-const MAX_TEMP: u8 = 85;
+    /// Write log lines to this file instead of stderr.
+    #[arg(long, value_name = "PATH")]
+    log_file: Option<std::path::PathBuf>,
 
-// Wait time between loops in ms.
-const REFRESH_RATE: u64 = 250;
+    /// Rename log file with .1 suffix and start fresh one when it would grow over this many bytes.
+    #[arg(long, value_name = "BYTES", default_value_t = LOG_MAX_BYTES_DEFAULT)]
+    log_max_bytes: u64,
 
-// Wait this many milliseconds to change speed when temperature is raising.
-const REACTION_TIME_MS_RAISE: u64 = 1000;
-// Wait this many milliseconds to change speed when temperature is lowering or staying same.
-const REACTION_TIME_MS_LOWER: u64 = 2000;
+    /// Path to configuration file.
+    #[arg(long, value_name = "PATH")]
+    config: Option<std::path::PathBuf>,
 
-// Increment as percentage to raise fan speed.
-const FAN_RAISE_INCREMENT: u8 = 5;
-// Increment as percentage to lower fan speed.
-const FAN_LOWER_INCREMENT: u8 = 1;
+    /// Preset of temperature limits, increments and reaction times, other overrides apply on top of it.
+    #[arg(long, value_enum)]
+    profile: Option<Profile>,
 
-// How many milliseconds to wait for command flag.
-const COMMAND_FLAG_MAX_WAIT_MS: u128 = 1000;
+    /// Override wait time between loops in milliseconds.
+    #[arg(long, value_name = "MS")]
+    refresh_ms: Option<u64>,
 
-// Command line arguments.
-#[derive(Debug, clap::Parser)]
-struct Cli {
-    #[command(flatten)]
-    verbose: clap_verbosity_flag::Verbosity,
+    /// Log temperature and RPM only every this many milliseconds, defaults to every loop.
+    /// Fan speed changes and warnings are always logged.
+    #[arg(long, value_name = "MS")]
+    log_interval_ms: Option<u64>,
+
+    /// Profile to switch to when running on battery.
+    #[arg(long, value_enum, value_name = "PROFILE")]
+    battery_profile: Option<Profile>,
+
+    /// Profile to switch to when running on AC power.
+    #[arg(long, value_enum, value_name = "PROFILE")]
+    ac_profile: Option<Profile>,
+
+    /// Override how many percents fan speed is raised at a time.
+    #[arg(long, value_name = "PERCENT")]
+    raise_increment: Option<u8>,
+
+    /// Override how many percents fan speed is lowered at a time.
+    #[arg(long, value_name = "PERCENT")]
+    lower_increment: Option<u8>,
+
+    /// Treat system as idle when 1 minute load average is under this, lowering fan speed slower
+    /// and keeping it under idle ceiling.
+    #[arg(long, value_name = "LOAD")]
+    idle_load: Option<f32>,
+
+    /// While this file exists, lift fan speed ceiling to 100 % and raise fan speed faster.
+    #[arg(long, value_name = "PATH")]
+    turbo_flag_file: Option<std::path::PathBuf>,
+
+    /// Only apply new fan speed when it differs from current one by more than this many percents.
+    #[arg(long, value_name = "PERCENT")]
+    deadband: Option<u8>,
+
+    /// Keep current fan speed instead of lowering it while temperature over minimum stays the same.
+    #[arg(long)]
+    hold_on_steady: bool,
+
+    /// Experimental: lengthen reaction times when fan speed oscillates up and down,
+    /// shorten them when fan speed keeps raising behind temperature.
+    #[arg(long)]
+    adaptive: bool,
+
+    /// Override minimum temperature from start to raise fan speed.
+    #[arg(long, value_name = "CELSIUS")]
+    min_temp: Option<u8>,
+
+    /// Override maximum temperature that starts raising fan speed even if temperature is not raising.
+    #[arg(long, value_name = "CELSIUS")]
+    max_temp: Option<u8>,
+
+    /// Override temperature that sets max fan speed right away, ignoring reaction times, increments and curve.
+    #[arg(long, value_name = "CELSIUS")]
+    critical_temp: Option<u8>,
+
+    /// Algorithm used to decide fan speed.
+    #[arg(long, value_enum)]
+    mode: Option<Mode>,
+
+    /// How many degrees temperature must drop under the one that last raised fan speed before lowering.
+    #[arg(long, value_name = "CELSIUS")]
+    hysteresis: Option<u8>,
+
+    /// Turn fan fully off under this temperature, bypassing minimum fan speed.
+    #[arg(long, value_name = "CELSIUS")]
+    passive_below: Option<u8>,
+
+    /// Stop fan under this temperature and keep it at minimum speed between this and --start-temp.
+    #[arg(long, value_name = "CELSIUS", requires = "start_temp")]
+    stop_temp: Option<u8>,
+
+    /// Resume normal control once temperature rises over this, starting stopped fan again.
+    #[arg(long, value_name = "CELSIUS", requires = "stop_temp")]
+    start_temp: Option<u8>,
+
+    /// Keep fan running at least this many milliseconds after it starts before letting passive mode stop it.
+    #[arg(long, value_name = "MS")]
+    min_on_ms: Option<u64>,
+
+    /// Keep fan stopped at least this many milliseconds in passive mode before starting it again,
+    /// unless temperature goes over maximum.
+    #[arg(long, value_name = "MS")]
+    min_off_ms: Option<u64>,
+
+    /// Raise fan speed without waiting for reaction time when temperature rises faster than this.
+    #[arg(long, value_name = "CELSIUS_PER_SECOND")]
+    fast_rise: Option<f32>,
+
+    /// Keep cooling this many milliseconds after fan speed would first be lowered.
+    #[arg(long, value_name = "MS")]
+    cooldown_ms: Option<u64>,
+
+    /// Limit how many percents applied fan speed can change per write.
+    #[arg(long, value_name = "PERCENT")]
+    max_step: Option<u8>,
+
+    /// Show temperatures in output as fahrenheit, thresholds stay in celsius.
+    #[arg(long)]
+    fahrenheit: bool,
+
+    /// Speed as percentage to start fans at, clamped to fan speed limits, defaults to minimum speed.
+    #[arg(long, value_name = "PERCENT")]
+    start_speed: Option<u8>,
+
+    /// Ids of fans to manage.
+    #[arg(long, value_name = "IDS", value_delimiter = ',', default_values_t = [FAN_ID])]
+    fans: Vec<u8>,
+
+    /// Embedded controller temperature sensor index of each fan in --fans order,
+    /// fans without one read sensor with same index as fan.
+    #[arg(long, value_name = "IDS", value_delimiter = ',', value_parser = parse_byte)]
+    ec_sensors: Vec<u8>,
+
+    /// Log intended fan speed writes instead of writing them to embedded controller.
+    #[arg(long)]
+    dry_run: bool,
+
+    /// Use given temperature instead of reading it from embedded controller in dry run.
+    #[arg(long, value_name = "CELSIUS", requires = "dry_run")]
+    fake_temp: Option<u8>,
+
+    /// Where to read temperatures from.
+    #[arg(long, value_enum, default_value_t = TempSource::Ec)]
+    temp_source: TempSource,
+
+    /// Path to hwmon temperature input used by sysfs temperature source.
+    #[arg(long, value_name = "PATH", default_value = HWMON_PATH_DEFAULT)]
+    hwmon_path: std::path::PathBuf,
+
+    /// Number of thermal zone used by thermal zone temperature source,
+    /// defaults to first zone of x86_pkg_temp type.
+    #[arg(long, value_name = "N")]
+    thermal_zone: Option<u32>,
+
+    /// Paths to additional hwmon temperature inputs combined with main temperature source.
+    #[arg(long, value_name = "PATHS", value_delimiter = ',')]
+    extra_hwmon_paths: Vec<std::path::PathBuf>,
+
+    /// How to combine temperatures when additional hwmon inputs are given.
+    #[arg(long, value_enum, default_value_t = Aggregate::Max)]
+    aggregate: Aggregate,
+
+    /// Weights of main temperature source followed by additional hwmon inputs for weighted aggregate.
+    #[arg(long, value_name = "WEIGHTS", value_delimiter = ',')]
+    weights: Vec<f32>,
+
+    /// Temperature resolution, 1 reads whole degrees and 2 reads second byte of fraction from
+    /// embedded controller or tenths from sysfs, so that control works in tenths of degree.
+    #[arg(
+        long,
+        value_name = "BYTES",
+        default_value_t = 1,
+        value_parser = clap::value_parser!(u8).range(1..=2)
+    )]
+    temp_resolution: u8,
+
+    /// How many temperature samples to average, 1 disables smoothing.
+    #[arg(
+        long,
+        value_name = "SAMPLES",
+        default_value_t = 1,
+        value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..)
+    )]
+    smoothing_window: usize,
+
+    /// How many consecutive temperature read failures to tolerate before exiting.
+    #[arg(long, value_name = "COUNT", default_value_t = 5)]
+    max_read_failures: u32,
+
+    /// Embedded controller temperature reading that is skipped as invalid instead of used.
+    #[arg(long, value_name = "VALUE", default_value_t = TEMP_INVALID, value_parser = parse_byte)]
+    invalid_temp: u8,
+
+    /// Discard reading that differs from smoothed temperature by more than this many degrees as glitch
+    /// and reuse previous temperature, initializing embedded controller again after --max-read-failures
+    /// glitches in a row.
+    #[arg(long, value_name = "DEGREES")]
+    max_temp_jump: Option<u8>,
+
+    /// Initialize embedded controller again after this many consecutive invalid temperature readings.
+    #[arg(long, value_name = "COUNT")]
+    reinit_after_invalid: Option<u32>,
+
+    /// How many times to retry embedded controller init before giving up.
+    #[arg(long, value_name = "COUNT", default_value_t = INIT_RETRIES)]
+    init_retries: u32,
+
+    /// How many milliseconds to wait between embedded controller init retries at most,
+    /// wait doubles after every failed retry up to this.
+    #[arg(long, value_name = "MS", default_value_t = INIT_MAX_BACKOFF_MS)]
+    init_max_backoff_ms: u64,
+
+    /// How many milliseconds to wait before first embedded controller access,
+    /// for firmware that is not ready right after cold boot.
+    #[arg(long, value_name = "MS", default_value_t = 0)]
+    boot_delay_ms: u64,
+
+    /// Run fans at max speed from background thread when control loop has not completed
+    /// in this many refresh rates, 0 disables.
+    #[arg(long, value_name = "LOOPS", default_value_t = STALL_LOOPS)]
+    stall_loops: u64,
+
+    /// Print effective configuration after profile, config file and overrides as TOML and exit.
+    #[arg(long)]
+    dump_config: bool,
+
+    /// Do not write start speed, keep current fan state until controller has made first decision.
+    #[arg(long)]
+    no_initial_write: bool,
+
+    /// Log minimum, average and maximum embedded controller command flag wait of every loop.
+    #[arg(long)]
+    log_timing: bool,
+
+    /// How many milliseconds to wait for embedded controller command flag.
+    #[arg(long, value_name = "MS", default_value_t = COMMAND_FLAG_MAX_WAIT_MS)]
+    flag_timeout_ms: u64,
+
+    /// Embedded controller command port, as hex with 0x prefix or decimal.
+    #[arg(long, value_name = "PORT", default_value_t = EC_COMMAND_PORT, value_parser = parse_port)]
+    command_port: u16,
+
+    /// Embedded controller data port, as hex with 0x prefix or decimal.
+    #[arg(long, value_name = "PORT", default_value_t = EC_DATA_PORT, value_parser = parse_port)]
+    data_port: u16,
+
+    /// Write raw speed inversely, --speed-max-raw meaning off, for firmware that reads it that way.
+    ///
+    /// On firmware that reads speed normally this runs fan fast when it should be slow
+    /// and stops it when it should run at max, so confirm direction with --verify-speed and
+    /// low set-speed values first.
+    #[arg(long)]
+    invert_speed: bool,
+
+    /// Raw speed value that firmware reads as full speed, for firmware that expects for example 0-100
+    /// or 0-229 instead of 0-255.
+    #[arg(long, value_name = "RAW", default_value_t = u8::MAX, value_parser = clap::value_parser!(u8).range(1..))]
+    speed_max_raw: u8,
+
+    /// Bits of speed register that hold fan speed, as hex with 0x prefix or decimal, overriding config.
+    /// When not all bits, current register is read and other bits are written back unchanged.
+    #[arg(long, value_name = "MASK", value_parser = parse_byte)]
+    speed_mask: Option<u8>,
+
+    /// How many bits fan speed is shifted left in speed register, overriding config.
+    #[arg(long, value_name = "BITS", value_parser = clap::value_parser!(u8).range(0..=7))]
+    speed_shift: Option<u8>,
+
+    /// Embedded controller command byte followed by data bytes sent to stop fan instead of
+    /// writing speed 0, as hex with 0x prefix or decimal separated by commas.
+    #[arg(long, value_name = "BYTES", value_delimiter = ',', value_parser = parse_byte)]
+    fan_off_command: Vec<u8>,
+
+    /// Embedded controller command byte followed by data bytes sent before first speed
+    /// after fan was stopped with --fan-off-command.
+    #[arg(long, value_name = "BYTES", value_delimiter = ',', value_parser = parse_byte)]
+    fan_resume_command: Vec<u8>,
+
+    /// When starting stopped fan, write this higher speed first so fan has torque to start spinning.
+    #[arg(long, value_name = "PERCENT", value_parser = clap::value_parser!(u8).range(1..=100))]
+    spinup_kick: Option<u8>,
+
+    /// How many milliseconds to run stopped fan at --spinup-kick speed before dropping to wanted speed.
+    #[arg(long, value_name = "MS", default_value_t = SPINUP_MS)]
+    spinup_ms: u64,
+
+    /// On SIGTERM or SIGINT, ramp fans from their current speed down to minimum speed over a second
+    /// before restoring automatic fan control.
+    #[arg(long)]
+    shutdown_ramp: bool,
+
+    /// Read fan speed back from embedded controller after every write and warn if it differs.
+    #[arg(long)]
+    verify_speed: bool,
+
+    /// Run fans at max speed on startup and check that they spin before controlling them.
+    #[arg(long)]
+    spin_test: bool,
+
+    /// Initialize embedded controller and set fan speeds again when resume from suspend is detected.
+    #[arg(long)]
+    resume_reinit: bool,
+
+    /// Shell command to run when temperature stays over maximum temperature,
+    /// temperature is given as first argument and in FAN_TEMP environment variable.
+    #[arg(long, value_name = "COMMAND")]
+    on_overheat: Option<String>,
+
+    /// How many consecutive loops temperature must be over maximum temperature to run overheat command.
+    #[arg(long, value_name = "LOOPS", default_value_t = 20)]
+    overheat_loops: u32,
+
+    /// Run single control iteration and exit without restoring automatic fan control.
+    #[arg(long)]
+    once: bool,
+
+    /// Append temperatures, fan speeds and RPMs of every loop to CSV file.
+    #[arg(long, value_name = "PATH")]
+    log_csv: Option<std::path::PathBuf>,
+
+    /// Address to serve Prometheus metrics from, for example 0.0.0.0:9100.
+    #[arg(long, value_name = "HOST:PORT")]
+    metrics_addr: Option<String>,
+
+    /// MQTT broker to publish temperatures and fan speeds to, for example mqtt://localhost:1883.
+    #[cfg(feature = "mqtt")]
+    #[arg(long, value_name = "URL")]
+    mqtt_broker: Option<String>,
+
+    /// Topic prefix to publish temp and speed topics under.
+    #[cfg(feature = "mqtt")]
+    #[arg(long, value_name = "PREFIX", default_value = "clevo-fan")]
+    mqtt_prefix: String,
+
+    /// InfluxDB write URL to post temperatures and fan speeds to as line protocol,
+    /// for example http://localhost:8086/write?db=fans. Points are written at log interval.
+    #[cfg(feature = "influx")]
+    #[arg(long, value_name = "URL")]
+    influx_url: Option<String>,
+
+    /// Serve temperature, fan speed and profile switching on D-Bus system bus.
+    #[cfg(feature = "dbus")]
+    #[arg(long)]
+    dbus: bool,
+
+    /// Write process id to this file and refuse to start if process in it is still running.
+    #[arg(long, value_name = "PATH")]
+    pid_file: Option<std::path::PathBuf>,
+
+    /// Path of Unix socket that writes JSON status snapshot to every client connecting to it.
+    #[arg(long, value_name = "PATH")]
+    status_socket: Option<std::path::PathBuf>,
+}
+
+// Subcommands that run once instead of controlling the fans.
+#[derive(Debug, clap::Subcommand)]
+enum Command {
+    /// Read temperature of fan once and print it.
+    ReadTemp {
+        /// Id of fan to read temperature of.
+        #[arg(long, value_name = "ID", default_value_t = FAN_ID)]
+        fan: u8,
+
+        /// Index of embedded controller temperature sensor to read instead of sensor of fan.
+        #[arg(long, value_name = "ID", value_parser = parse_byte)]
+        sensor: Option<u8>,
+
+        /// Print temperature as fahrenheit.
+        #[arg(long)]
+        fahrenheit: bool,
+    },
+    /// List hwmon temperature inputs with their names, labels and current readings,
+    /// to find path for --hwmon-path. Needs no root.
+    ListSensors,
+    /// Advanced diagnostic: read embedded controller RAM and print it as hex table.
+    ///
+    /// Reading registers of unknown embedded controller is unsafe and may confuse firmware,
+    /// only use this for reverse engineering on hardware you can recover.
+    DumpEc {
+        /// Address to start reading from, as hex with 0x prefix or decimal.
+        #[arg(value_parser = parse_byte)]
+        start: u8,
+
+        /// How many bytes to read, reading stops at end of address space.
+        count: u16,
+    },
+    /// Set fan to fixed speed once and exit.
+    SetSpeed {
+        /// Speed as percentage, clamped to allowed fan speeds.
+        percent: u8,
+
+        /// Id of fan to set speed of.
+        #[arg(long, value_name = "ID", default_value_t = FAN_ID)]
+        fan: u8,
+    },
+    /// Replay recorded temperature trace through fan controller and print its decisions,
+    /// without touching embedded controller.
+    ///
+    /// Trace is CSV with timestamp in seconds and temperature columns, files written with --log-csv work too.
+    Simulate {
+        /// Path to trace CSV file.
+        trace: std::path::PathBuf,
+
+        /// Id of fan to simulate, also picks rows of that fan from --log-csv files.
+        #[arg(long, value_name = "ID", default_value_t = FAN_ID)]
+        fan: u8,
+    },
+    /// Step fan from minimum to maximum speed, dwelling at each, and write speed, resulting RPM
+    /// and temperature to CSV to help build fan curve. Automatic fan control is restored after.
+    Capture {
+        /// Path of CSV file to write.
+        output: std::path::PathBuf,
+
+        /// Id of fan to capture.
+        #[arg(long, value_name = "ID", default_value_t = FAN_ID)]
+        fan: u8,
+
+        /// How many percents to raise speed between steps.
+        #[arg(long, value_name = "PERCENT", default_value_t = CAPTURE_STEP, value_parser = clap::value_parser!(u8).range(1..))]
+        step: u8,
+
+        /// How many milliseconds to dwell at each speed before recording.
+        #[arg(long, value_name = "MS", default_value_t = CAPTURE_DWELL_MS)]
+        dwell_ms: u64,
+    },
+    /// Print ASCII plot of steady state fan speed that configuration gives at every temperature,
+    /// without touching embedded controller.
+    Preview {
+        /// Id of fan whose speed limits to use.
+        #[arg(long, value_name = "ID", default_value_t = FAN_ID)]
+        fan: u8,
+    },
+    /// Check that fan control works and print pass or fail report for bug reports:
+    /// IO permission, temperature reading, writing and reading back speed, and RPM.
+    /// Automatic fan control is restored after.
+    Diagnose {
+        /// Id of fan to check.
+        #[arg(long, value_name = "ID", default_value_t = FAN_ID)]
+        fan: u8,
+    },
+    /// Run fan controller and draw live dashboard of temperature, target and applied speed,
+    /// RPM and loop counters. Press q to quit and restore automatic fan control.
+    Monitor,
 }
 
-// Failed to set IO permission error.
-#[derive(Debug)]
-pub(crate) struct FailedToSetIOPermissionError {
-    port: u16,
-    return_value: i32,
+// Format of log lines.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+enum LogFormat {
+    // Human readable lines.
+    Text,
+    // JSON object per line with timestamp, level, temperature, fan speed and message.
+    #[cfg(feature = "json-log")]
+    Json,
 }
-impl FailedToSetIOPermissionError {
-    pub fn new(port: u16, return_value: i32) -> FailedToSetIOPermissionError {
-        FailedToSetIOPermissionError { port, return_value }
-    }
+
+// Source to read temperatures from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+enum TempSource {
+    // Embedded controller temperature of fan.
+    Ec,
+    // Hwmon temperature input from sysfs.
+    Sysfs,
+    // Thermal zone temperature from sysfs.
+    ThermalZone,
 }
-impl std::error::Error for FailedToSetIOPermissionError {}
-impl std::fmt::Display for FailedToSetIOPermissionError {
-    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        write!(
-            f,
-            "Failed to set IO permission to port {} got return value {}!",
-            self.port, self.return_value
-        )
+
+// Parse number given as hex with 0x prefix or as decimal.
+fn parse_hex(value: &str) -> Result<u16, std::num::ParseIntError> {
+    return match value
+        .strip_prefix("0x")
+        .or_else(|| value.strip_prefix("0X"))
+    {
+        Some(hex) => u16::from_str_radix(hex, 16),
+        None => value.parse::<u16>(),
+    };
+}
+
+// Parse IO port given as hex with 0x prefix or as decimal.
+fn parse_port(value: &str) -> Result<u16, String> {
+    return parse_hex(value).map_err(|error| format!("invalid port {}: {}", value, error));
+}
+
+// Parse byte, like embedded controller RAM address, given as hex with 0x prefix or as decimal.
+fn parse_byte(value: &str) -> Result<u8, String> {
+    let byte: u16 =
+        parse_hex(value).map_err(|error| format!("invalid byte {}: {}", value, error))?;
+    return u8::try_from(byte).map_err(|_| format!("{} is over 0xFF", value));
+}
+
+// Load configuration from command line given path or from default path if it exists
+// and apply command line overrides on top of it.
+fn config_from_cli(cli: &Cli, profile: Option<Profile>) -> Result<Config, ConfigError> {
+    let mut config: Config = match &cli.config {
+        Some(path) => Config::load(path)?,
+        None => {
+            let path: &std::path::Path = std::path::Path::new(CONFIG_PATH_DEFAULT);
+            if path.exists() {
+                Config::load(path)?
+            } else {
+                Config::default()
+            }
+        }
+    };
+    if let Some(profile) = profile {
+        config.apply_profile(profile);
+    }
+    if let Some(refresh_ms) = cli.refresh_ms {
+        config.refresh_rate = refresh_ms;
+    }
+    if let Some(raise_increment) = cli.raise_increment {
+        config.fan_raise_increment = raise_increment;
+    }
+    if let Some(lower_increment) = cli.lower_increment {
+        config.fan_lower_increment = lower_increment;
+    }
+    if let Some(min_temp) = cli.min_temp {
+        config.min_temp = min_temp;
+    }
+    if let Some(max_temp) = cli.max_temp {
+        config.max_temp = max_temp;
+    }
+    if cli.critical_temp.is_some() {
+        config.critical_temp = cli.critical_temp;
+    }
+    if let Some(mode) = cli.mode {
+        config.mode = mode;
+    }
+    if let Some(hysteresis) = cli.hysteresis {
+        config.hysteresis = hysteresis;
     }
+    if cli.passive_below.is_some() {
+        config.passive_below = cli.passive_below;
+    }
+    if cli.stop_temp.is_some() {
+        config.stop_temp = cli.stop_temp;
+        config.start_temp = cli.start_temp;
+    }
+    if cli.max_step.is_some() {
+        config.max_step = cli.max_step;
+    }
+    if let Some(deadband) = cli.deadband {
+        config.deadband = deadband;
+    }
+    if cli.hold_on_steady {
+        config.hold_on_steady = true;
+    }
+    if cli.adaptive {
+        config.adaptive = true;
+    }
+    if cli.idle_load.is_some() {
+        config.idle_load = cli.idle_load;
+    }
+    if !cli.fan_off_command.is_empty() {
+        config.fan_off_sequence = Some(cli.fan_off_command.clone());
+    }
+    if !cli.fan_resume_command.is_empty() {
+        config.fan_resume_sequence = Some(cli.fan_resume_command.clone());
+    }
+    if cli.fast_rise.is_some() {
+        config.fast_rise = cli.fast_rise;
+    }
+    if let Some(cooldown_ms) = cli.cooldown_ms {
+        config.cooldown_ms = cooldown_ms;
+    }
+    if let Some(min_on_ms) = cli.min_on_ms {
+        config.min_on_ms = min_on_ms;
+    }
+    if let Some(min_off_ms) = cli.min_off_ms {
+        config.min_off_ms = min_off_ms;
+    }
+    if let Some(speed_mask) = cli.speed_mask {
+        config.speed_mask = speed_mask;
+    }
+    if let Some(speed_shift) = cli.speed_shift {
+        config.speed_shift = speed_shift;
+    }
+    return Ok(config);
 }
 
-// Set IO permission to port.
-fn set_port_io_permission(port: u16) -> Result<(), FailedToSetIOPermissionError> {
-    let return_value: i32 = unsafe { libc::ioperm(port as u64, 1, 1) };
-    if return_value != 0 {
-        return Err(FailedToSetIOPermissionError::new(port, return_value));
+// Load and validate configuration.
+fn load_config(cli: &Cli, profile: Option<Profile>) -> Result<Config, RunError> {
+    let config: Config = config_from_cli(cli, profile)?;
+    config.validate()?;
+    if config.reaction_loops(config.reaction_time_ms_raise) == 1
+        || config.reaction_loops(config.reaction_time_ms_lower) == 1
+    {
+        log::warn!(
+            "Refresh rate {} ms is so large that reaction time smoothing is a single loop.",
+            config.refresh_rate
+        );
     }
-    return Ok(());
+    return Ok(config);
 }
 
-// Initialize embedded controller.
-fn ec_init() -> Result<(), FailedToSetIOPermissionError> {
-    set_port_io_permission(EC_DATA_PORT)?;
-    set_port_io_permission(EC_COMMAND_PORT)?;
-    return Ok(());
+// Fan speeds written by control loop, shared with signal handler so that it can ramp fans down
+// from them and stop control loop from writing once shutdown starts.
+#[derive(Debug, Default)]
+struct SpeedGate {
+    // Is process shutting down, so that control loop must not write anymore.
+    closed: bool,
+    // Last written speed and minimum speed of every fan that has been written to.
+    fans: std::collections::BTreeMap<u8, (u8, u8)>,
 }
 
-// Command flag wait timed out error.
-#[derive(Debug)]
-pub(crate) struct CommandFlagWaitTimedOutError {
-    flag: Flag,
-    on: bool,
+// Fan whose writes go through speed gate.
+struct GatedFan {
+    fan_id: u8,
+    fan_speed_min: u8,
+    fan: Box<dyn FanSpeedSink>,
+    gate: std::sync::Arc<std::sync::Mutex<SpeedGate>>,
 }
-impl CommandFlagWaitTimedOutError {
-    pub fn new(flag: Flag, on: bool) -> CommandFlagWaitTimedOutError {
-        CommandFlagWaitTimedOutError { flag, on }
+impl FanSpeedSink for GatedFan {
+    fn set(&mut self, percent: u8) -> Result<(), CommandFlagWaitTimedOutError> {
+        // Hold gate while writing, so shutdown can not start between check and write.
+        let mut gate: std::sync::MutexGuard<SpeedGate> = match self.gate.lock() {
+            Ok(gate) => gate,
+            Err(error) => error.into_inner(),
+        };
+        if gate.closed {
+            return Ok(());
+        }
+        self.fan.set(percent)?;
+        gate.fans.insert(self.fan_id, (percent, self.fan_speed_min));
+        return Ok(());
     }
 }
-impl std::error::Error for CommandFlagWaitTimedOutError {}
-impl std::fmt::Display for CommandFlagWaitTimedOutError {
-    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        write!(
-            f,
-            "Timed out when waiting for command flag {:?} to be {}!",
-            self.flag,
-            (if self.on { "on" } else { "off" })
-        )
+
+// Fan managed by the control loop with its own speed state.
+struct ManagedFan {
+    fan_id: u8,
+    temp_source: Box<dyn TemperatureSource>,
+    smoother: TempSmoother,
+    fan: Box<dyn FanSpeedSink>,
+    controller: FanController,
+    // How many consecutive temperature reads have failed.
+    read_failures: u32,
+    // How many consecutive temperature readings have been invalid.
+    invalid_reads: u32,
+    // How many consecutive temperature readings have been discarded as glitches.
+    glitch_reads: u32,
+    // How many consecutive loops temperature has been over maximum temperature.
+    overheat_loops: u32,
+    // How many loops since temperature was last logged.
+    log_loops: u64,
+    // When kicked fan drops from spin-up kick speed to wanted speed.
+    kick_until: Option<std::time::Instant>,
+    // Fan speed last written to fan, kick speed while kicking, FAN_SPEED_UNKNOWN until first write.
+    applied_speed: u8,
+}
+impl ManagedFan {
+    // Set fan speed and remember it as applied one.
+    fn set(&mut self, speed: u8) -> Result<(), CommandFlagWaitTimedOutError> {
+        self.fan.set(speed)?;
+        self.applied_speed = speed;
+        return Ok(());
+    }
+}
+
+// Where values of every control loop are recorded to.
+struct Outputs {
+    csv_log: Option<CsvLog>,
+    metrics: std::sync::Arc<std::sync::Mutex<Metrics>>,
+    status: std::sync::Arc<std::sync::Mutex<Status>>,
+    histogram: std::sync::Arc<std::sync::Mutex<Histogram>>,
+    #[cfg(feature = "mqtt")]
+    mqtt: Option<MqttPublisher>,
+    #[cfg(feature = "influx")]
+    influx: Option<InfluxWriter>,
+    #[cfg(feature = "dbus")]
+    dbus: Option<DbusService>,
+    monitor: Option<Monitor>,
+}
+impl Outputs {
+    // Record temperature, speed and RPM of fan after control loop.
+    fn record(&mut self, managed: &ManagedFan, temp: u8, rpm: Option<u16>) {
+        let fan_speed: u8 = managed.applied_speed;
+
+        // Log to CSV.
+        if let Some(csv_log) = &mut self.csv_log {
+            csv_log.write_row(managed.fan_id, temp, fan_speed, rpm);
+        }
+
+        // Update metrics.
+        if let Ok(mut metrics) = self.metrics.lock() {
+            metrics.update(managed.fan_id, temp, fan_speed, rpm);
+        }
+
+        // Publish to MQTT.
+        #[cfg(feature = "mqtt")]
+        if let Some(mqtt) = &mut self.mqtt {
+            mqtt.publish(managed.fan_id, temp, fan_speed);
+        }
+
+        // Write to InfluxDB on loops that temperature was logged at.
+        #[cfg(feature = "influx")]
+        if let Some(influx) = &self.influx {
+            if managed.log_loops == 0 {
+                influx.write(managed.fan_id, temp, fan_speed);
+            }
+        }
+
+        // Share with D-Bus service.
+        #[cfg(feature = "dbus")]
+        if let Some(dbus) = &self.dbus {
+            dbus.update(managed.fan_id, temp, fan_speed);
+        }
+
+        // Update histogram.
+        if let Ok(mut histogram) = self.histogram.lock() {
+            histogram.record(managed.fan_id, temp, fan_speed);
+        }
+
+        // Update status.
+        if let Ok(mut status) = self.status.lock() {
+            status.update(
+                managed.fan_id,
+                FanStatus {
+                    temp,
+                    fan_speed,
+                    raising_loops: managed.controller.raising_loops(),
+                    lowering_or_staying_loops: managed.controller.lowering_or_staying_loops(),
+                },
+            );
+        }
+
+        // Update monitor.
+        if let Some(monitor) = &mut self.monitor {
+            monitor.update(
+                managed.fan_id,
+                MonitorRow {
+                    temp,
+                    target: managed.controller.fan_speed(),
+                    fan_speed,
+                    rpm,
+                    raising_loops: managed.controller.raising_loops(),
+                    lowering_or_staying_loops: managed.controller.lowering_or_staying_loops(),
+                },
+            );
+        }
     }
 }
 
-#[derive(Debug, Clone)]
-enum Flag {
-    OBF,
-    IBF,
+// Initialize embedded controller, retrying with exponential backoff since ports may not be ready at boot.
+fn init_with_retry(
+    ec: Ec,
+    retries: u32,
+    max_backoff_ms: u64,
+) -> Result<(), FailedToSetIOPermissionError> {
+    let mut backoff_ms: u64 = std::cmp::min(INIT_BACKOFF_MS, max_backoff_ms);
+    let mut attempt: u32 = 0;
+    loop {
+        match ec.init() {
+            Ok(()) => return Ok(()),
+            // Retrying does not help when not running as root.
+            Err(error) if error.permission_denied() || retries <= attempt => return Err(error),
+            Err(error) => {
+                attempt += 1;
+                log::warn!(
+                    "Failed to initialize embedded controller, retry {}/{} in {} ms: {}",
+                    attempt,
+                    retries,
+                    backoff_ms,
+                    error
+                );
+                std::thread::sleep(std::time::Duration::from_millis(backoff_ms));
+                backoff_ms = std::cmp::min(backoff_ms.saturating_mul(2), max_backoff_ms);
+            }
+        }
+    }
 }
-impl Flag {
-    // Flag as u8 value.
-    fn flag(&self) -> u8 {
-        return match self {
-            Flag::OBF => 0x1,
-            Flag::IBF => 0x2,
+
+// Run fan controller.
+fn run(
+    cli: &Cli,
+    ec: Ec,
+    reload: &std::sync::atomic::AtomicBool,
+    histogram: &std::sync::Arc<std::sync::Mutex<Histogram>>,
+    gate: &std::sync::Arc<std::sync::Mutex<SpeedGate>>,
+) -> Result<(), RunError> {
+    // Load configuration.
+    let mut profile: Option<Profile> = cli.profile;
+    let mut config: Config = load_config(cli, profile)?;
+    if !cli.weights.is_empty() && cli.weights.len() != cli.extra_hwmon_paths.len() + 1 {
+        return Err(ConfigError::InvalidValueError(
+            "weights".to_string(),
+            format!(
+                "expected {} weights for main temperature source and additional hwmon inputs, got {}",
+                cli.extra_hwmon_paths.len() + 1,
+                cli.weights.len()
+            ),
+        )
+        .into());
+    }
+    log::debug!("Using config: {:?}", config);
+
+    // Init enbedded controller, unless dry run does not need it for reading temperatures.
+    if cli.dry_run {
+        log::info!("Dry run, fan speeds will not be written.");
+    }
+    let ec_available: bool =
+        !cli.dry_run || (cli.fake_temp.is_none() && cli.temp_source == TempSource::Ec);
+    if ec_available {
+        if 0 < cli.boot_delay_ms {
+            log::info!(
+                "Waiting {} ms before accessing embedded controller.",
+                cli.boot_delay_ms
+            );
+            std::thread::sleep(std::time::Duration::from_millis(cli.boot_delay_ms));
+        }
+        init_with_retry(ec, cli.init_retries, cli.init_max_backoff_ms)?;
+    }
+    notify_ready();
+
+    // Start metrics server if address was given.
+    let metrics: std::sync::Arc<std::sync::Mutex<Metrics>> =
+        std::sync::Arc::new(std::sync::Mutex::new(Metrics::default()));
+    if let Some(addr) = &cli.metrics_addr {
+        start_metrics_server(addr, metrics.clone())?;
+    }
+
+    // Start status socket if path was given.
+    let status: std::sync::Arc<std::sync::Mutex<Status>> =
+        std::sync::Arc::new(std::sync::Mutex::new(Status::new()));
+    if let Some(path) = &cli.status_socket {
+        start_status_socket(path, status.clone())?;
+    }
+
+    // Open CSV log if path was given.
+    let mut outputs: Outputs = Outputs {
+        csv_log: cli.log_csv.as_deref().map(CsvLog::open),
+        metrics,
+        status,
+        histogram: histogram.clone(),
+        #[cfg(feature = "mqtt")]
+        mqtt: cli
+            .mqtt_broker
+            .as_deref()
+            .map(|broker| MqttPublisher::connect(broker, &cli.mqtt_prefix, 1 < cli.fans.len())),
+        #[cfg(feature = "influx")]
+        influx: cli
+            .influx_url
+            .as_deref()
+            .map(|url| InfluxWriter::start(url, 1 < cli.fans.len())),
+        #[cfg(feature = "dbus")]
+        dbus: if cli.dbus {
+            Some(DbusService::start()?)
+        } else {
+            None
+        },
+        monitor: None,
+    };
+
+    // Init managed fans.
+    let mut fans: Vec<ManagedFan> = Vec::new();
+    for fan_id in &cli.fans {
+        // Write fan speeds to embedded controller or only log them in dry run.
+        let sink: Box<dyn FanSpeedSink> = if cli.dry_run {
+            Box::new(DryRunFan::new(*fan_id))
+        } else {
+            Box::new(EcFan::new(
+                ec,
+                *fan_id,
+                config.fan_off_sequence.clone(),
+                config.fan_resume_sequence.clone(),
+            ))
         };
+        let fan_speed_min: u8 = config.fan_speed_min_for(*fan_id);
+        let mut fan: Box<dyn FanSpeedSink> = Box::new(GatedFan {
+            fan_id: *fan_id,
+            fan_speed_min,
+            fan: sink,
+            gate: gate.clone(),
+        });
+        // Set start speed within fan speed limits.
+        let start_speed: u8 = cli
+            .start_speed
+            .unwrap_or(fan_speed_min)
+            .clamp(fan_speed_min, config.fan_speed_max_for(*fan_id));
+        if cli.no_initial_write {
+            log::info!("Starting fan {} without writing speed", fan_id);
+        } else {
+            log::info!("Starting fan {} at {} %", fan_id, start_speed);
+            fan.set(start_speed)?;
+        }
+
+        // Read temperatures from embedded controller or use fake temperature.
+        let mut temp_source: Box<dyn TemperatureSource> = match (cli.fake_temp, cli.temp_source) {
+            (Some(temp), _) => Box::new(FakeTemperature::new(temp)),
+            (None, TempSource::Ec) => Box::new(EcTemperature::new(
+                ec,
+                ec_sensor(cli, *fan_id),
+                cli.invalid_temp,
+            )),
+            (None, TempSource::Sysfs) => Box::new(SysfsTemperature::new(cli.hwmon_path.clone())),
+            (None, TempSource::ThermalZone) => {
+                Box::new(ThermalZoneTemperature::new(thermal_zone(cli)))
+            }
+        };
+        // Combine with additional hwmon inputs if given.
+        if !cli.extra_hwmon_paths.is_empty() {
+            let mut aggregator: Aggregator = Aggregator::new(cli.aggregate);
+            aggregator.add(temp_source, cli.weights.first().copied().unwrap_or(1.0));
+            for (index, path) in cli.extra_hwmon_paths.iter().enumerate() {
+                aggregator.add(
+                    Box::new(SysfsTemperature::new(path.clone())),
+                    cli.weights.get(index + 1).copied().unwrap_or(1.0),
+                );
+            }
+            temp_source = Box::new(aggregator);
+        }
+        // Smooth temperatures over configured window.
+        let smoother: TempSmoother = TempSmoother::new(cli.smoothing_window);
+
+        // Init controller from first temperature and start speed, without pushing it to smoother
+        // so that first loop sample is not weighted twice.
+        let first: u16 = read_sample(cli, temp_source.as_mut())?;
+        let mut controller: FanController = FanController::new(
+            &config.for_fan(*fan_id),
+            tenths_to_celsius(sample_to_tenths(cli, first)),
+        );
+        if cli.no_initial_write {
+            controller.assume_speed(start_speed);
+        } else {
+            controller.force_speed(start_speed);
+        }
+
+        let applied_speed: u8 = controller.fan_speed_last();
+        fans.push(ManagedFan {
+            fan_id: *fan_id,
+            temp_source,
+            smoother,
+            fan,
+            controller,
+            read_failures: 0,
+            invalid_reads: 0,
+            glitch_reads: 0,
+            overheat_loops: 0,
+            // Log first loop right away.
+            log_loops: u64::MAX,
+            kick_until: None,
+            applied_speed,
+        });
     }
 
-    // Is flag on in given output?
-    fn on(&self, output: u8) -> bool {
-        let flag: u8 = self.flag();
-        return output & flag == flag;
+    // Make sure fans spin before trusting the controller.
+    if cli.spin_test {
+        spin_test(&config, ec, ec_available, &mut fans)?;
     }
 
-    // Wait for flag to be on.
-    fn wait_for_on(&self) -> Result<(), CommandFlagWaitTimedOutError> {
-        return self.wait(true);
+    // Measure CPU utilization between loops.
+    let mut cpu_usage: CpuUsage = CpuUsage::new();
+
+    // Run single control iteration and exit if asked.
+    if cli.once {
+        return control_fans(
+            cli,
+            &config,
+            ec,
+            ec_available,
+            &mut fans,
+            &mut outputs,
+            &mut cpu_usage,
+        );
     }
 
-    // Wait for flag to be off.
-    fn wait_for_off(&self) -> Result<(), CommandFlagWaitTimedOutError> {
-        return self.wait(false);
+    // Draw live dashboard if monitoring.
+    if is_monitor(cli) {
+        outputs.monitor = Some(Monitor::start()?);
     }
 
-    // Wait for flag to be given on status.
-    fn wait(&self, on: bool) -> Result<(), CommandFlagWaitTimedOutError> {
-        // Command port with readonly access.
-        let mut port_command: x86_64::instructions::port::PortGeneric<
-            u8,
-            x86_64::instructions::port::ReadOnlyAccess,
-        > = x86_64::instructions::port::PortReadOnly::<u8>::new(EC_COMMAND_PORT);
+    // Sleep milli second amount, changes only when configuration is reloaded.
+    let mut sleep_time: std::time::Duration = std::time::Duration::from_millis(config.refresh_rate);
 
-        // Init start time.
-        let start: std::time::Instant = std::time::Instant::now();
+    // Watch for stalled loop from background thread.
+    let watchdog: std::sync::Arc<LoopWatchdog> = std::sync::Arc::new(LoopWatchdog::new(
+        cli.stall_loops.saturating_mul(config.refresh_rate),
+    ));
+    if 0 < cli.stall_loops {
+        start_loop_watchdog(watchdog.clone(), ec, cli.fans.clone(), cli.dry_run);
+    }
+
+    // Watch power state if profiles are switched by it.
+    let mut power_monitor: Option<PowerMonitor> =
+        if cli.battery_profile.is_some() || cli.ac_profile.is_some() {
+            Some(PowerMonitor::start())
+        } else {
+            None
+        };
+
+    // Infinite loop.
+    loop {
+        // Reload configuration also when power state changes to switch profile.
+        let mut reload_config: bool = reload.swap(false, std::sync::atomic::Ordering::SeqCst);
+        if let Some(on_ac) = power_monitor.as_mut().and_then(|monitor| monitor.poll()) {
+            let power_profile: Option<Profile> = if on_ac {
+                cli.ac_profile
+            } else {
+                cli.battery_profile
+            };
+            log::info!(
+                "Running on {}, switching to profile {:?}.",
+                if on_ac { "AC" } else { "battery" },
+                power_profile.or(cli.profile)
+            );
+            profile = power_profile.or(cli.profile);
+            reload_config = true;
+        }
+
+        // Switch to profile asked over D-Bus.
+        #[cfg(feature = "dbus")]
+        if let Some(dbus_profile) = outputs.dbus.as_ref().and_then(|dbus| dbus.take_profile()) {
+            log::info!("Switching to profile {:?} asked over D-Bus.", dbus_profile);
+            profile = Some(dbus_profile);
+            reload_config = true;
+        }
+
+        // Reload configuration if asked with SIGHUP, keeping old one if new one is broken.
+        if reload_config {
+            match load_config(cli, profile) {
+                Ok(new_config) => {
+                    log::info!("Reloaded config.");
+                    log::debug!("Using config: {:?}", new_config);
+                    for managed in fans.iter_mut() {
+                        managed
+                            .controller
+                            .reload(&new_config.for_fan(managed.fan_id));
+                    }
+                    config = new_config;
+                    sleep_time = std::time::Duration::from_millis(config.refresh_rate);
+                    watchdog.set_timeout_ms(cli.stall_loops.saturating_mul(config.refresh_rate));
+                }
+                Err(error) => log::error!("Failed to reload config, keeping old one: {}", error),
+            }
+        }
+
+        control_fans(
+            cli,
+            &config,
+            ec,
+            ec_available,
+            &mut fans,
+            &mut outputs,
+            &mut cpu_usage,
+        )?;
+        watchdog.feed();
 
-        // Wait for flag to be given on status.
-        while self.on(unsafe { port_command.read() }) != on {
-            // If max timeout has been reached
-            if COMMAND_FLAG_MAX_WAIT_MS < start.elapsed().as_millis() {
-                // return error.
-                return Err(CommandFlagWaitTimedOutError::new(self.clone(), on));
+        // Tell D-Bus clients about new temperature.
+        #[cfg(feature = "dbus")]
+        if let Some(dbus) = &outputs.dbus {
+            dbus.emit();
+        }
+
+        // Continue from max speed that watchdog forced fans to while loop was stalled.
+        if watchdog.take_forced() {
+            for managed in fans.iter_mut() {
+                let fan_speed_max: u8 = config.fan_speed_max_for(managed.fan_id);
+                managed.set(fan_speed_max)?;
+                managed.controller.force_speed(fan_speed_max);
             }
         }
 
-        // Flag was set to asked on status.
-        return Ok(());
+        // Draw monitor and stop controlling when asked to quit.
+        if let Some(monitor) = &outputs.monitor {
+            monitor.draw()?;
+            if monitor.quit_requested()? {
+                log::info!("Quit from monitor.");
+                return Ok(());
+            }
+        }
+
+        // Output how long embedded controller took to respond during loop.
+        if cli.log_timing {
+            log_flag_wait_timings();
+        }
+
+        // Tell watchdog that loop completed.
+        notify_watchdog();
+
+        // Sleep set milli seconds.
+        // Monotonic clock stops during suspend, so measure sleep with wall clock.
+        let sleep_start: std::time::SystemTime = std::time::SystemTime::now();
+        std::thread::sleep(sleep_time);
+        let slept: std::time::Duration = sleep_start.elapsed().unwrap_or_default();
+
+        // Sleeping far longer than asked means machine was suspended.
+        if cli.resume_reinit && sleep_time + std::time::Duration::from_millis(RESUME_GAP_MS) < slept
+        {
+            reinit_after_resume(ec, ec_available, &mut fans)?;
+        }
+    }
+}
+
+// Run one control iteration: read temperature, decide and apply speed of every managed fan.
+fn control_fans(
+    cli: &Cli,
+    config: &Config,
+    ec: Ec,
+    ec_available: bool,
+    fans: &mut [ManagedFan],
+    outputs: &mut Outputs,
+    cpu_usage: &mut CpuUsage,
+) -> Result<(), RunError> {
+    // Sleep milli second amount.
+    let sleep_time: std::time::Duration = std::time::Duration::from_millis(config.refresh_rate);
+
+    // Read load once per loop for idle detection.
+    let idle: Option<bool> = config
+        .idle_load
+        .and_then(|idle_load| load_average().map(|load| load < idle_load));
+
+    // Sample CPU utilization once per loop if fan is raised by it.
+    let cpu_utilization: Option<f32> = if 0.0 < config.cpu_weight {
+        cpu_usage.sample()
+    } else {
+        None
+    };
+
+    // Check turbo flag file once per loop.
+    let turbo: Option<bool> = cli.turbo_flag_file.as_ref().map(|path| path.exists());
+
+    // Most consecutive temperature read failures of any fan this loop, to back off by.
+    let mut backoff_failures: u32 = 0;
+
+    for managed in fans.iter_mut() {
+        if let Some(idle) = idle {
+            managed.controller.set_idle(idle);
+        }
+        if let Some(turbo) = turbo {
+            managed.controller.set_turbo(turbo);
+        }
+        if let Some(cpu_utilization) = cpu_utilization {
+            managed.controller.set_cpu_utilization(cpu_utilization);
+        }
+
+        // Get smoothed temperature.
+        let temp_tenths: u16 = match read_sample(cli, managed.temp_source.as_mut()) {
+            Ok(sample) => {
+                managed.read_failures = 0;
+                managed.invalid_reads = 0;
+                let sample: u16 = filter_glitch(cli, ec, ec_available, managed, sample);
+                sample_to_tenths(cli, managed.smoother.push(sample))
+            }
+            // Skip invalid readings until there has been too many of them.
+            Err(TemperatureReadError::InvalidTemperatureReadingError(error))
+                if managed.invalid_reads < cli.max_read_failures =>
+            {
+                skip_invalid_reading(cli, ec, ec_available, managed, error);
+                continue;
+            }
+            Err(error) => {
+                managed.read_failures += 1;
+                // Give up after too many consecutive failures.
+                if cli.max_read_failures <= managed.read_failures {
+                    return Err(error.into());
+                }
+                log::error!(
+                    "Failed to read fan {} temperature {}/{}, running at max speed: {}",
+                    managed.fan_id,
+                    managed.read_failures,
+                    cli.max_read_failures,
+                    error
+                );
+                // Run at max speed while temperature is unknown.
+                let fan_speed_max: u8 = config.fan_speed_max_for(managed.fan_id);
+                managed.set(fan_speed_max)?;
+                managed.controller.force_speed(fan_speed_max);
+                // Back off once per loop after all fans, so failing fan does not slow others.
+                backoff_failures = std::cmp::max(backoff_failures, managed.read_failures);
+                continue;
+            }
+        };
+
+        let temp: u8 = tenths_to_celsius(temp_tenths);
+
+        // Read RPM when embedded controller is available.
+        let rpm: Option<u16> = if ec_available {
+            match ec.get_fan_rpm(managed.fan_id) {
+                Ok(rpm) => Some(rpm),
+                Err(error) => {
+                    log::warn!("Failed to read fan {} RPM: {}", managed.fan_id, error);
+                    None
+                }
+            }
+        } else {
+            None
+        };
+
+        // Print out temperature and RPM every log interval.
+        let log_every: u64 = match cli.log_interval_ms {
+            Some(log_interval_ms) => {
+                std::cmp::max(log_interval_ms / std::cmp::max(config.refresh_rate, 1), 1)
+            }
+            None => 1,
+        };
+        managed.log_loops = managed.log_loops.saturating_add(1);
+        if log_every <= managed.log_loops {
+            managed.log_loops = 0;
+            log_temperature(cli, managed, temp, temp_tenths, rpm);
+        }
+
+        // If fan should be spinning but is not, warn about it.
+        if rpm == Some(0) && 0 < managed.applied_speed && managed.applied_speed != FAN_SPEED_UNKNOWN
+        {
+            log::warn!(
+                "Fan {} RPM is zero while speed is {} %, fan may be seized!",
+                managed.fan_id,
+                managed.applied_speed
+            );
+        }
+
+        // Drop kicked fan to wanted speed once kick has lasted long enough.
+        if managed
+            .kick_until
+            .is_some_and(|until| until <= std::time::Instant::now())
+        {
+            managed.kick_until = None;
+            let fan_speed: u8 = managed.controller.fan_speed_last();
+            managed.set(fan_speed)?;
+
+            // Make sure embedded controller accepted the speed.
+            if cli.verify_speed && !cli.dry_run {
+                verify_fan_speed(ec, managed.fan_id, fan_speed);
+            }
+        }
+
+        // If controller decided to change fan speed
+        let fan_speed_last: u8 = managed.controller.fan_speed_last();
+        if let Some(fan_speed) = managed.controller.step_tenths(temp_tenths) {
+            // kick stopped fan to get it spinning, keeping kick speed until its deadline on later loop
+            let kicking: bool = match cli.spinup_kick {
+                Some(kick) if 0 < fan_speed && fan_speed < kick => {
+                    if fan_speed_last == 0 {
+                        log::info!(
+                            "Kicking fan {} at {} % for {} ms to start it",
+                            managed.fan_id,
+                            kick,
+                            cli.spinup_ms
+                        );
+                        managed.set(kick)?;
+                        managed.kick_until = Some(
+                            std::time::Instant::now()
+                                + std::time::Duration::from_millis(cli.spinup_ms),
+                        );
+
+                        // Make sure embedded controller accepted the kick speed.
+                        if cli.verify_speed && !cli.dry_run {
+                            verify_fan_speed(ec, managed.fan_id, kick);
+                        }
+                    }
+                    managed.kick_until.is_some()
+                }
+                _ => false,
+            };
+
+            // or set it.
+            if !kicking {
+                managed.kick_until = None;
+                managed.set(fan_speed)?;
+
+                // Make sure embedded controller accepted the speed.
+                if cli.verify_speed && !cli.dry_run {
+                    verify_fan_speed(ec, managed.fan_id, fan_speed);
+                }
+            }
+        }
+
+        // Run overheat command if temperature has stayed over maximum.
+        if let Some(command) = &cli.on_overheat {
+            check_overheat(cli, config, managed, temp, command);
+        }
+
+        // Record values of loop.
+        outputs.record(managed, temp, rpm);
     }
+
+    // Back off before retrying failed temperature reads.
+    if 0 < backoff_failures {
+        std::thread::sleep(sleep_time * backoff_failures);
+    }
+
+    return Ok(());
 }
 
-// Write given value to given port.
-fn write_to_port(port: u16, value: u8) -> Result<(), CommandFlagWaitTimedOutError> {
-    // Wait for input buffer flag to be off.
-    Flag::IBF.wait_for_off()?;
+// Run fans at max speed for a while and check from RPM that they spin.
+fn spin_test(
+    config: &Config,
+    ec: Ec,
+    ec_available: bool,
+    fans: &mut [ManagedFan],
+) -> Result<(), RunError> {
+    log::info!("Spin testing fans at max speed.");
+    for managed in fans.iter_mut() {
+        managed.set(config.fan_speed_max_for(managed.fan_id))?;
+    }
+    std::thread::sleep(std::time::Duration::from_millis(SPIN_TEST_MS));
 
-    // Write the value to port.
-    unsafe { x86_64::instructions::port::PortWriteOnly::<u8>::new(port).write(value) };
+    for managed in fans.iter_mut() {
+        // RPM can only be checked from embedded controller.
+        if ec_available {
+            match ec.get_fan_rpm(managed.fan_id) {
+                Ok(0) => log::error!(
+                    "FAN {} DID NOT SPIN IN SPIN TEST, RPM IS ZERO AT MAX SPEED! Fan may be dead.",
+                    managed.fan_id
+                ),
+                Ok(rpm) => log::info!("Fan {} spin test passed at {} RPM.", managed.fan_id, rpm),
+                Err(error) => log::warn!(
+                    "Failed to read fan {} RPM in spin test: {}",
+                    managed.fan_id,
+                    error
+                ),
+            }
+        }
+        // Return to start speed before control loop.
+        let fan_speed: u8 = managed.controller.fan_speed();
+        managed.set(fan_speed)?;
+    }
+    return Ok(());
+}
 
-    Ok(())
+// Initialize embedded controller again and set fan speeds that it lost during suspend.
+fn reinit_after_resume(
+    ec: Ec,
+    ec_available: bool,
+    fans: &mut [ManagedFan],
+) -> Result<(), RunError> {
+    log::info!("Resume from suspend detected, initializing embedded controller again.");
+    if ec_available {
+        ec.init()?;
+    }
+    for managed in fans.iter_mut() {
+        let fan_speed: u8 = managed.applied_speed;
+        managed.set(fan_speed)?;
+    }
+    return Ok(());
 }
 
-// Send command to embedded controller.
-fn send_command(command: u8) -> Result<(), CommandFlagWaitTimedOutError> {
-    return write_to_port(EC_COMMAND_PORT, command);
+// Count loops over maximum temperature and run overheat command once per overheat.
+fn check_overheat(cli: &Cli, config: &Config, managed: &mut ManagedFan, temp: u8, command: &str) {
+    if temp <= config.max_temp {
+        managed.overheat_loops = 0;
+        return;
+    }
+    managed.overheat_loops = managed.overheat_loops.saturating_add(1);
+    // Run only when limit is reached, so command is not run again while still over.
+    if managed.overheat_loops != std::cmp::max(cli.overheat_loops, 1) {
+        return;
+    }
+
+    log::warn!(
+        "Fan {} temperature {} has been over maximum for {} loops, running overheat command.",
+        managed.fan_id,
+        format_temp(temp, cli.fahrenheit),
+        managed.overheat_loops
+    );
+    match std::process::Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .arg("sh")
+        .arg(temp.to_string())
+        .env("FAN_TEMP", temp.to_string())
+        .env("FAN_ID", managed.fan_id.to_string())
+        .spawn()
+    {
+        // Wait for command on background thread so control loop is not blocked.
+        Ok(mut child) => {
+            std::thread::spawn(move || {
+                if let Err(error) = child.wait() {
+                    log::warn!("Failed to wait for overheat command: {}", error);
+                }
+            });
+        }
+        Err(error) => log::error!("Failed to run overheat command: {}", error),
+    }
 }
 
-// Write data to embedded controller.
-fn write_data(data: u8) -> Result<(), CommandFlagWaitTimedOutError> {
-    return write_to_port(EC_DATA_PORT, data);
+// Log skipped invalid temperature reading and initialize embedded controller again if asked.
+fn skip_invalid_reading(
+    cli: &Cli,
+    ec: Ec,
+    ec_available: bool,
+    managed: &mut ManagedFan,
+    error: InvalidTemperatureReadingError,
+) {
+    managed.invalid_reads += 1;
+    log::warn!(
+        "Skipping invalid reading {}/{}: {}",
+        managed.invalid_reads,
+        cli.max_read_failures,
+        error
+    );
+    if ec_available && cli.reinit_after_invalid == Some(managed.invalid_reads) {
+        log::info!("Initializing embedded controller again.");
+        if let Err(error) = ec.init() {
+            log::warn!("Failed to initialize embedded controller again: {}", error);
+        }
+    }
 }
 
-// Set data speed.
-fn set_fan_speed(speed: u8) -> Result<(), CommandFlagWaitTimedOutError> {
-    send_command(COMMAND_SPEED)?;
-    write_data(FAN_ID)?;
-    return write_data(
-        ((std::cmp::min(speed, 100) as f32 / 100 as f32) * 255 as f32).floor() as u8,
+// Replace reading that jumped implausibly far from smoothed temperature with previous one.
+// After too many in a row jump is accepted as real and embedded controller is initialized again.
+fn filter_glitch(
+    cli: &Cli,
+    ec: Ec,
+    ec_available: bool,
+    managed: &mut ManagedFan,
+    sample: u16,
+) -> u16 {
+    let (max_jump, mean): (u8, u16) = match (cli.max_temp_jump, managed.smoother.mean()) {
+        (Some(max_jump), Some(mean)) => (max_jump, mean),
+        _ => return sample,
+    };
+    if sample_to_tenths(cli, sample.abs_diff(mean)) <= celsius_to_tenths(max_jump) {
+        managed.glitch_reads = 0;
+        return sample;
+    }
+
+    managed.glitch_reads += 1;
+    if cli.max_read_failures <= managed.glitch_reads {
+        log::warn!(
+            "Fan {} temperature jumped {} times in a row, accepting it and initializing embedded controller again.",
+            managed.fan_id,
+            managed.glitch_reads
+        );
+        managed.glitch_reads = 0;
+        if ec_available {
+            if let Err(error) = ec.init() {
+                log::warn!("Failed to initialize embedded controller again: {}", error);
+            }
+        }
+        return sample;
+    }
+    log::debug!(
+        "Discarding fan {} glitch reading {} {}/{}, smoothed is {}",
+        managed.fan_id,
+        format_sample(cli, sample_to_tenths(cli, sample)),
+        managed.glitch_reads,
+        cli.max_read_failures,
+        format_sample(cli, sample_to_tenths(cli, mean))
     );
+    return mean;
+}
+
+// Read fan speed back from embedded controller and warn if it differs from requested one.
+fn verify_fan_speed(ec: Ec, fan_id: u8, fan_speed: u8) {
+    // Conversion through raw value gives back same percentage, so compare directly.
+    match ec.get_fan_speed(fan_id) {
+        Ok(speed) if speed != fan_speed => log::warn!(
+            "Fan {} speed read back as {} % after setting it to {} %!",
+            fan_id,
+            speed,
+            fan_speed
+        ),
+        Ok(_) => {}
+        Err(error) => log::warn!("Failed to read back fan {} speed: {}", fan_id, error),
+    }
+}
+
+// Tell systemd that service has started.
+#[cfg(feature = "systemd")]
+fn notify_ready() {
+    if let Err(error) = sd_notify::notify(false, &[sd_notify::NotifyState::Ready]) {
+        log::warn!("Failed to notify systemd about readiness: {}", error);
+    }
+}
+#[cfg(not(feature = "systemd"))]
+fn notify_ready() {}
+
+// Tell systemd watchdog that control loop is still running.
+#[cfg(feature = "systemd")]
+fn notify_watchdog() {
+    if let Err(error) = sd_notify::notify(false, &[sd_notify::NotifyState::Watchdog]) {
+        log::warn!("Failed to ping systemd watchdog: {}", error);
+    }
+}
+#[cfg(not(feature = "systemd"))]
+fn notify_watchdog() {}
+
+// Log flag wait durations recorded since last call.
+fn log_flag_wait_timings() {
+    let timings: FlagWaitTimings = take_flag_wait_timings();
+    match (timings.min, timings.avg(), timings.max) {
+        (Some(min), Some(avg), Some(max)) => log::info!(
+            "Command flag waits: {}, min {:?}, avg {:?}, max {:?}",
+            timings.count,
+            min,
+            avg,
+            max
+        ),
+        _ => log::info!("Command flag waits: 0"),
+    }
+}
+
+// Embedded controller temperature sensor of fan given from command line or sensor with same index as fan.
+fn ec_sensor(cli: &Cli, fan_id: u8) -> u8 {
+    return cli
+        .fans
+        .iter()
+        .position(|id| *id == fan_id)
+        .and_then(|index| cli.ec_sensors.get(index))
+        .copied()
+        .unwrap_or(fan_id);
+}
+
+// Thermal zone given from command line or CPU package zone, falling back to first zone.
+fn thermal_zone(cli: &Cli) -> u32 {
+    if let Some(zone) = cli.thermal_zone {
+        return zone;
+    }
+    return match find_thermal_zone(THERMAL_ZONE_TYPE_CPU) {
+        Some(zone) => {
+            log::info!(
+                "Using thermal zone {} of type {}",
+                zone,
+                THERMAL_ZONE_TYPE_CPU
+            );
+            zone
+        }
+        None => {
+            log::warn!(
+                "No thermal zone of type {} found, using thermal zone 0",
+                THERMAL_ZONE_TYPE_CPU
+            );
+            0
+        }
+    };
+}
+
+// Read temperature sample in resolution given from command line,
+// whole degrees by default or tenths of degree with higher resolution.
+fn read_sample(cli: &Cli, source: &mut dyn TemperatureSource) -> Result<u16, TemperatureReadError> {
+    if cli.temp_resolution == 2 {
+        return source.read_tenths();
+    }
+    return Ok(source.read()? as u16);
 }
 
-// Flush embedded controller.
-fn ec_flush() {
-    // Init readonly access to command and data ports.
-    let mut port_command: x86_64::instructions::port::PortGeneric<
-        u8,
-        x86_64::instructions::port::ReadOnlyAccess,
-    > = x86_64::instructions::port::PortReadOnly::<u8>::new(EC_COMMAND_PORT);
-    let mut port_data: x86_64::instructions::port::PortGeneric<
-        u8,
-        x86_64::instructions::port::ReadOnlyAccess,
-    > = x86_64::instructions::port::PortReadOnly::<u8>::new(EC_DATA_PORT);
+// Convert temperature sample in resolution given from command line to tenths of degree.
+fn sample_to_tenths(cli: &Cli, sample: u16) -> u16 {
+    if cli.temp_resolution == 2 {
+        return sample;
+    }
+    return celsius_to_tenths(std::cmp::min(sample, u8::MAX as u16) as u8);
+}
 
-    // While output buffer flag is on
-    while Flag::OBF.on(unsafe { port_command.read() }) {
-        // read data.
-        unsafe { port_data.read() };
+// Log temperature and RPM of fan.
+fn log_temperature(cli: &Cli, managed: &ManagedFan, temp: u8, temp_tenths: u16, rpm: Option<u16>) {
+    match rpm {
+        Some(rpm) => log::info!(
+            temp = temp, fan_speed = managed.applied_speed;
+            "Fan {} temperature: {}, RPM: {}",
+            managed.fan_id,
+            format_sample(cli, temp_tenths),
+            rpm
+        ),
+        None => log::info!(
+            temp = temp, fan_speed = managed.applied_speed;
+            "Fan {} temperature: {}",
+            managed.fan_id,
+            format_sample(cli, temp_tenths)
+        ),
     }
 }
 
-// Read byte from enbedded controller.
-fn read_byte() -> Result<u8, CommandFlagWaitTimedOutError> {
-    // Wait for output buffer flag to be on.
-    Flag::OBF.wait_for_on()?;
+// Format temperature for output, with one decimal place when reading higher resolution.
+fn format_sample(cli: &Cli, tenths: u16) -> String {
+    if cli.temp_resolution == 2 {
+        return format_tenths(tenths, cli.fahrenheit);
+    }
+    return format_temp(tenths_to_celsius(tenths), cli.fahrenheit);
+}
 
-    // Return read byte.
-    Ok(unsafe { x86_64::instructions::port::PortReadOnly::<u8>::new(EC_DATA_PORT).read() })
+// Read temperature of sensor once and print it.
+fn read_temp(ec: Ec, sensor_id: u8, fahrenheit: bool) -> Result<(), RunError> {
+    ec.init()?;
+    let temp: u8 = ec.get_temp(sensor_id)?;
+    println!("{}", format_temp(temp, fahrenheit));
+    return Ok(());
 }
 
-// Get local temperature.
-fn get_local_temp() -> Result<u8, CommandFlagWaitTimedOutError> {
-    ec_flush();
-    send_command(COMMAND_TEMP)?;
-    write_data(FAN_ID)?;
-    return read_byte();
+// Set fan to fixed speed once and print what was written.
+fn set_speed(ec: Ec, fan_id: u8, percent: u8) -> Result<(), RunError> {
+    let speed: u8 = percent.clamp(FAN_SPEED_MIN, FAN_SPEED_MAX);
+    ec.init()?;
+    ec.set_fan_speed(fan_id, speed)?;
+    println!(
+        "Set fan {} speed to {} % (raw {}/{})",
+        fan_id,
+        speed,
+        percent_to_raw(speed, ec.speed_max_raw()),
+        ec.speed_max_raw()
+    );
+    return Ok(());
 }
 
-// Collects all of the errors that can occur when creating a new connection.
-#[derive(Debug)]
-pub(crate) enum RunError {
-    FailedToSetIOPermissionError(FailedToSetIOPermissionError),
-    CommandFlagWaitTimedOutError(CommandFlagWaitTimedOutError),
+// Capture fan curve and restore automatic fan control also when capture fails or is interrupted.
+fn capture(
+    cli: &Cli,
+    ec: Ec,
+    output: &std::path::Path,
+    fan_id: u8,
+    step: u8,
+    dwell_ms: u64,
+) -> Result<(), RunError> {
+    let config: Config = load_config(cli, cli.profile)?.for_fan(fan_id);
+    ec.init()?;
+    let result: Result<(), RunError> = capture_steps(&config, ec, output, fan_id, step, dwell_ms);
+    match ec.restore_auto_fan() {
+        Ok(()) => println!("Restored automatic fan control."),
+        Err(error) => eprintln!("Failed to restore automatic fan control {}", error),
+    }
+    return result;
 }
-impl std::fmt::Display for RunError {
-    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        match self {
-            RunError::FailedToSetIOPermissionError(e) => {
-                write!(f, "Failed to set IO permission error:\n{}", e)
+
+// Step fan from minimum to maximum speed and write speed, RPM and temperature at each to CSV.
+fn capture_steps(
+    config: &Config,
+    ec: Ec,
+    output: &std::path::Path,
+    fan_id: u8,
+    step: u8,
+    dwell_ms: u64,
+) -> Result<(), RunError> {
+    let to_error = |error: std::io::Error| -> FailedToCaptureError {
+        return FailedToCaptureError::new(output.to_path_buf(), error.to_string());
+    };
+
+    // Stop capturing on Ctrl-C or SIGTERM instead of exiting with fan at fixed speed.
+    let stop: std::sync::Arc<std::sync::atomic::AtomicBool> =
+        std::sync::Arc::new(std::sync::atomic::AtomicBool::new(false));
+    for signal in [signal_hook::consts::SIGINT, signal_hook::consts::SIGTERM] {
+        signal_hook::flag::register(signal, stop.clone()).map_err(to_error)?;
+    }
+
+    let mut file: std::fs::File = std::fs::File::create(output).map_err(to_error)?;
+    std::io::Write::write_all(&mut file, b"speed,rpm,temp\n").map_err(to_error)?;
+
+    // Speeds from minimum to maximum, always ending at maximum.
+    let mut speeds: Vec<u8> = (config.fan_speed_min..=config.fan_speed_max)
+        .step_by(step as usize)
+        .collect();
+    if speeds.last() != Some(&config.fan_speed_max) {
+        speeds.push(config.fan_speed_max);
+    }
+
+    for speed in speeds {
+        ec.set_fan_speed(fan_id, speed)?;
+
+        // Dwell in short sleeps to notice interruption.
+        let start: std::time::Instant = std::time::Instant::now();
+        while start.elapsed() < std::time::Duration::from_millis(dwell_ms) {
+            if stop.load(std::sync::atomic::Ordering::SeqCst) {
+                println!("Capture interrupted.");
+                return Ok(());
             }
-            RunError::CommandFlagWaitTimedOutError(e) => {
-                write!(f, "Command flag wait timed out error:\n{}", e)
+            std::thread::sleep(std::time::Duration::from_millis(CAPTURE_POLL_MS));
+        }
+
+        let rpm: u16 = ec.get_fan_rpm(fan_id)?;
+        let temp: u8 = ec.get_local_temp(fan_id)?;
+        println!("{} % {} RPM {}", speed, rpm, format_temp(temp, false));
+        std::io::Write::write_all(
+            &mut file,
+            format!("{},{},{}\n", speed, rpm, temp).as_bytes(),
+        )
+        .map_err(to_error)?;
+    }
+    println!("Captured fan curve to {}", output.display());
+    return Ok(());
+}
+
+// Print result of diagnostic check and return whether it passed.
+fn report_check(name: &str, result: Result<String, String>) -> bool {
+    return match result {
+        Ok(detail) => {
+            println!("[PASS] {}: {}", name, detail);
+            true
+        }
+        Err(detail) => {
+            println!("[FAIL] {}: {}", name, detail);
+            false
+        }
+    };
+}
+
+// Check everything fan control needs and print pass or fail report.
+fn diagnose(cli: &Cli, ec: Ec, fan_id: u8) -> Result<(), RunError> {
+    println!("Version: {}", env!("CARGO_PKG_VERSION"));
+    println!("Architecture: {}", std::env::consts::ARCH);
+    println!(
+        "Ports: command {:#x}, data {:#x}",
+        cli.command_port, cli.data_port
+    );
+    println!("Fan: {}, sensor: {}", fan_id, ec_sensor(cli, fan_id));
+
+    let mut results: Vec<bool> = Vec::new();
+
+    // Nothing else can be checked without access to ports.
+    let permission: bool = report_check(
+        "IO permission",
+        ec.init()
+            .map(|()| "ports accessible".to_string())
+            .map_err(|error| error.to_string()),
+    );
+    results.push(permission);
+    if permission {
+        let temp: Result<String, String> = match ec.get_temp(ec_sensor(cli, fan_id)) {
+            Ok(temp) if temp == cli.invalid_temp => Err(format!("got invalid reading {:#x}", temp)),
+            Ok(temp) if temp == 0 || DIAGNOSE_TEMP_MAX < temp => {
+                Err(format!("{} is not plausible", format_temp(temp, false)))
+            }
+            Ok(temp) => Ok(format_temp(temp, false)),
+            Err(error) => Err(error.to_string()),
+        };
+        results.push(report_check("Temperature", temp));
+
+        let speed: Result<String, String> = match ec
+            .set_fan_speed(fan_id, DIAGNOSE_SPEED)
+            .and_then(|()| ec.get_fan_speed(fan_id))
+        {
+            Ok(speed) if speed == DIAGNOSE_SPEED => Ok(format!("wrote and read back {} %", speed)),
+            Ok(speed) => Err(format!(
+                "wrote {} % but read back {} %",
+                DIAGNOSE_SPEED, speed
+            )),
+            Err(error) => Err(error.to_string()),
+        };
+        results.push(report_check("Speed write", speed));
+
+        let rpm: Result<String, String> = match ec.get_fan_rpm(fan_id) {
+            Ok(rpm) => Ok(format!("{} RPM", rpm)),
+            Err(error) => Err(error.to_string()),
+        };
+        results.push(report_check("RPM", rpm));
+
+        results.push(report_check(
+            "Restore automatic control",
+            ec.restore_auto_fan()
+                .map(|()| "restored".to_string())
+                .map_err(|error| error.to_string()),
+        ));
+    }
+
+    let failed: u32 = results.iter().filter(|passed| !**passed).count() as u32;
+    if 0 < failed {
+        return Err(DiagnosticsFailedError::new(failed, results.len() as u32).into());
+    }
+    println!("All {} checks passed.", results.len());
+    return Ok(());
+}
+
+// Print hwmon temperature inputs one per line.
+fn list_sensors() -> Result<(), RunError> {
+    let inputs: Vec<HwmonInput> = list_hwmon_inputs();
+    if inputs.is_empty() {
+        println!("No hwmon temperature inputs found.");
+    }
+    for input in inputs {
+        let temp: String = match input.temp {
+            Some(temp) => format!("{:.1} C", temp),
+            None => "unreadable".to_string(),
+        };
+        println!(
+            "{}  {}  {}  {}",
+            input.path.display(),
+            input.name,
+            input.label.as_deref().unwrap_or("-"),
+            temp
+        );
+    }
+    return Ok(());
+}
+
+// Read embedded controller RAM and print it as hex table with 16 bytes per row.
+fn dump_ec(ec: Ec, start: u8, count: u16) -> Result<(), RunError> {
+    ec.init()?;
+    // Do not read past end of address space.
+    let end: u16 = std::cmp::min((start as u16).saturating_add(count), 0x100);
+
+    println!(
+        "    {}",
+        (0..16)
+            .map(|column| format!("{:02X}", column))
+            .collect::<Vec<String>>()
+            .join(" ")
+    );
+    for row in ((start as u16 & 0xF0)..end).step_by(16) {
+        let mut line: String = format!("{:02X}: ", row);
+        for address in row..row + 16 {
+            if address < start as u16 || end <= address {
+                // Leave unread addresses empty.
+                line.push_str("   ");
+            } else {
+                line.push_str(&format!("{:02X} ", ec.read_register(address as u8)?));
             }
         }
+        println!("{}", line.trim_end());
     }
+    return Ok(());
 }
-impl From<FailedToSetIOPermissionError> for RunError {
-    fn from(err: FailedToSetIOPermissionError) -> Self {
-        RunError::FailedToSetIOPermissionError(err)
+
+// Replay temperature trace through fan controller and print fan speed after every sample.
+fn simulate(cli: &Cli, path: &std::path::Path, fan_id: u8) -> Result<(), RunError> {
+    let config: Config = load_config(cli, cli.profile)?.for_fan(fan_id);
+    let samples: Vec<TraceSample> = read_trace(path, fan_id)?;
+    let first: TraceSample = match samples.first() {
+        Some(first) => *first,
+        None => return Ok(()),
+    };
+
+    // Start like the control loop does.
+    let mut controller: FanController = FanController::new(&config, first.temp);
+    controller.force_speed(
+        cli.start_speed
+            .unwrap_or(config.fan_speed_min)
+            .clamp(config.fan_speed_min, config.fan_speed_max),
+    );
+
+    println!("timestamp,temp,fan_speed");
+    let mut timestamp_last: f64 = first.timestamp;
+    for sample in &samples {
+        // Controller counts loops, so step once per refresh that fits between samples.
+        let elapsed_ms: f64 = (sample.timestamp - timestamp_last).max(0.0) * 1000.0;
+        let loops: u64 = std::cmp::max(
+            (elapsed_ms / std::cmp::max(config.refresh_rate, 1) as f64).round() as u64,
+            1,
+        );
+        timestamp_last = sample.timestamp;
+
+        // Controller logs every change it decides.
+        for _ in 0..loops {
+            controller.step(sample.temp);
+        }
+        println!(
+            "{:.3},{},{}",
+            sample.timestamp,
+            sample.temp,
+            controller.fan_speed_last()
+        );
     }
+    return Ok(());
 }
-impl From<CommandFlagWaitTimedOutError> for RunError {
-    fn from(err: CommandFlagWaitTimedOutError) -> Self {
-        RunError::CommandFlagWaitTimedOutError(err)
+
+// Speed that controller settles to when temperature stays at given one.
+fn steady_speed(config: &Config, temp: u8) -> u8 {
+    let mut controller: FanController = FanController::new(config, temp);
+    controller.force_speed(config.fan_speed_min);
+    for _ in 0..config.reaction_loops(PREVIEW_SETTLE_MS) {
+        controller.step(temp);
     }
+    return controller.fan_speed_last();
 }
 
-// Run fan controller.
-fn run() -> Result<(), RunError> {
-    // Init logger from command line arguments.
-    env_logger::Builder::new()
-        .filter_level(<Cli as clap::Parser>::parse().verbose.log_level_filter())
-        .init();
+// Print plot of steady state fan speed over temperature range.
+fn preview(cli: &Cli, fan_id: u8) -> Result<(), RunError> {
+    let config: Config = load_config(cli, cli.profile)?.for_fan(fan_id);
+    // Controller logs of every simulated step would bury plot.
+    log::set_max_level(log::LevelFilter::Off);
+    let speeds: Vec<u8> = (PREVIEW_TEMP_MIN..=PREVIEW_TEMP_MAX)
+        .map(|temp| steady_speed(&config, temp))
+        .collect();
 
-    // Speed of fan when last set.
-    let mut fan_speed_last: u8 = 0;
-    // Set to this fan speed.
-    let mut fan_speed: u8 = FAN_SPEED_MIN;
+    // Row of every 10 %, marking temperatures whose speed rounds to it.
+    for level in (0..=10).rev() {
+        let row: String = speeds
+            .iter()
+            .map(|speed| {
+                if (*speed as u32 + 5) / 10 == level {
+                    return '*';
+                }
+                return ' ';
+            })
+            .collect();
+        println!("{:>3} % |{}", level * 10, row);
+    }
+    println!("      +{}", "-".repeat(speeds.len()));
+
+    // Temperature label under every 10 degrees.
+    let mut labels: String = String::new();
+    for temp in (PREVIEW_TEMP_MIN..=PREVIEW_TEMP_MAX).step_by(10) {
+        let column: usize = (temp - PREVIEW_TEMP_MIN) as usize;
+        labels.push_str(&" ".repeat(column.saturating_sub(labels.len())));
+        labels.push_str(&temp.to_string());
+    }
+    println!("       {} C", labels);
+    return Ok(());
+}
 
-    // Init enbedded controller.
-    ec_init()?;
+// Run subcommand.
+fn run_command(cli: &Cli, command: &Command, ec: Ec) -> Result<(), RunError> {
+    return match command {
+        Command::ReadTemp {
+            fan,
+            sensor,
+            fahrenheit,
+        } => read_temp(ec, sensor.unwrap_or(*fan), *fahrenheit),
+        Command::ListSensors => list_sensors(),
+        Command::DumpEc { start, count } => dump_ec(ec, *start, *count),
+        Command::SetSpeed { percent, fan } => set_speed(ec, *fan, *percent),
+        Command::Capture {
+            output,
+            fan,
+            step,
+            dwell_ms,
+        } => capture(cli, ec, output, *fan, *step, *dwell_ms),
+        Command::Simulate { trace, fan } => simulate(cli, trace, *fan),
+        Command::Diagnose { fan } => diagnose(cli, ec, *fan),
+        Command::Preview { fan } => preview(cli, *fan),
+        // Monitor runs fan controller instead.
+        Command::Monitor => Ok(()),
+    };
+}
 
-    // Set fan speed.
-    set_fan_speed(fan_speed)?;
+// Is fan controller run with live dashboard.
+fn is_monitor(cli: &Cli) -> bool {
+    return matches!(cli.command, Some(Command::Monitor));
+}
 
-    // Sleep milli second amount.
-    let sleep_time: std::time::Duration = std::time::Duration::from_millis(REFRESH_RATE);
-    // Wait this many loops to change speed when temperature is raising.
-    let reaction_loops_raise: u64 = REACTION_TIME_MS_RAISE / REFRESH_RATE;
-    // Wait this many loops to change speed when temperature is lowering or staying same.
-    let reaction_loops_lower: u64 = REACTION_TIME_MS_LOWER / REFRESH_RATE;
-
-    // Temperature.
-    let mut temp: u8 = get_local_temp()?;
-    // Last loop temperature.
-    let mut temp_last: u8 = temp.clone();
-
-    // How many loops has temperature been raising.
-    let mut raising_loops: u64 = 0;
-    // How many loops has temperature been lowering or staying the same.
-    let mut lowering_or_staying_loops: u64 = 0;
+// Restore automatic fan control from current thread.
+fn restore(ec: Ec) -> Result<(), RunError> {
+    // IO permissions are per thread, so set them for this one.
+    ec.init()?;
+    ec.restore_auto_fan()?;
+    return Ok(());
+}
 
-    // Infinite loop.
-    loop {
-        // Get temperature.
-        temp = get_local_temp()?;
-        // Print out temperature.
-        log::info!("Temperature: {} C", temp);
-
-        // If temperature is over the max
-        if MAX_TEMP < temp
-            // or
-            || (
-                // min temperature has been reached
-                MIN_TEMP < temp
-                // and 
-                &&
-                // temperature is raising.
-                temp_last < temp
-            )
-        {
-            // Output information about it.
-            log::info!("Raising or over max!");
+// Restore automatic fan control and output error if it fails.
+fn cleanup(ec: Ec, dry_run: bool) {
+    // Dry run never took control of the fan.
+    if dry_run {
+        return;
+    }
+    match restore(ec) {
+        Ok(()) => log::info!("Restored automatic fan control."),
+        Err(error) => eprintln!("Failed to restore automatic fan control {}", error),
+    }
+}
 
-            // Increase raising loops.
-            raising_loops += 1;
+// Print temperature histogram when verbose output is on.
+fn print_histogram(histogram: &std::sync::Mutex<Histogram>, verbose: bool) {
+    if !verbose {
+        return;
+    }
+    let text: String = match histogram.lock() {
+        Ok(histogram) => histogram.render(),
+        Err(error) => error.get_ref().render(),
+    };
+    println!("{}", text);
+}
 
-            // If has been raising more than reaction time gives.
-            if reaction_loops_raise < raising_loops {
-                // Raise fan speed by defined increment.
-                fan_speed = std::cmp::min(fan_speed + FAN_RAISE_INCREMENT, FAN_SPEED_MAX);
+// Ramp fans from their last written speeds down to their minimum speeds over shutdown ramp time.
+fn ramp_down(ec: Ec, gate: &SpeedGate) {
+    // IO permissions are per thread, so set them for this one.
+    if let Err(error) = ec.init() {
+        eprintln!("Failed to ramp down fans {}", error);
+        return;
+    }
+    for step in 1..=SHUTDOWN_RAMP_STEPS {
+        for (fan_id, (speed, fan_speed_min)) in &gate.fans {
+            let drop: u16 =
+                speed.saturating_sub(*fan_speed_min) as u16 * step / SHUTDOWN_RAMP_STEPS;
+            let ramped: u8 = (*speed as u16 - drop) as u8;
+            if let Err(error) = ec.set_fan_speed(*fan_id, ramped) {
+                eprintln!("Failed to ramp down fan {} {}", fan_id, error);
+                return;
+            }
+        }
+        std::thread::sleep(std::time::Duration::from_millis(
+            SHUTDOWN_RAMP_MS / SHUTDOWN_RAMP_STEPS as u64,
+        ));
+    }
+}
 
-                // Zero loop counters.
-                lowering_or_staying_loops = 0;
-                raising_loops = 0;
+// Install panic hook that runs fans at max speed before process dies, so crash fails loud but cool.
+fn install_panic_hook(ec: Ec, fan_ids: Vec<u8>, dry_run: bool) {
+    let default_hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        // Keep printing panic message.
+        default_hook(info);
+        if dry_run {
+            return;
+        }
+        // IO permissions are per thread, so set them for panicking one.
+        if let Err(error) = ec.init() {
+            eprintln!("Failed to run fans at max speed after panic {}", error);
+            return;
+        }
+        for fan_id in &fan_ids {
+            // Panic may have happened while holding embedded controller lock, so do not wait for it.
+            match ec.force_fan_speed(*fan_id, FAN_SPEED_MAX) {
+                Ok(()) => eprintln!("Set fan {} to {} % after panic", fan_id, FAN_SPEED_MAX),
+                Err(error) => eprintln!(
+                    "Failed to set fan {} to max speed after panic {}",
+                    fan_id, error
+                ),
             }
         }
-        // Fan speed is not raising or over the max.
-        else {
-            // Inform about it.
-            log::info!("Lowering or staying the same.");
-            // Increase loop counter.
-            lowering_or_staying_loops += 1;
-
-            // If has been lowering or staying the same, more than reaction time gives.
-            if reaction_loops_lower < lowering_or_staying_loops {
-                // Lower fan speed by defined increment.
-                if FAN_LOWER_INCREMENT < fan_speed {
-                    fan_speed = std::cmp::max(fan_speed - FAN_LOWER_INCREMENT, FAN_SPEED_MIN);
-                } else {
-                    fan_speed = FAN_SPEED_MIN;
-                }
+    }));
+}
 
-                // Zero loop counters.
-                lowering_or_staying_loops = 0;
-                raising_loops = 0;
+// Install handler that optionally ramps fans down, restores automatic fan control, removes PID file,
+// prints histogram and exits on SIGTERM and SIGINT.
+fn install_signal_handler(
+    ec: Ec,
+    dry_run: bool,
+    pid_file: Option<std::path::PathBuf>,
+    histogram: std::sync::Arc<std::sync::Mutex<Histogram>>,
+    gate: std::sync::Arc<std::sync::Mutex<SpeedGate>>,
+    shutdown_ramp: bool,
+    verbose: bool,
+) -> Result<(), std::io::Error> {
+    let mut signals: signal_hook::iterator::Signals = signal_hook::iterator::Signals::new([
+        signal_hook::consts::SIGTERM,
+        signal_hook::consts::SIGINT,
+    ])?;
+    std::thread::spawn(move || {
+        if let Some(signal) = signals.forever().next() {
+            log::info!("Got signal {}, exiting.", signal);
+            // Closing gate stops control loop from writing after this, and holding it until exit
+            // waits for write in progress to finish first.
+            let mut gate: std::sync::MutexGuard<SpeedGate> = match gate.lock() {
+                Ok(gate) => gate,
+                Err(error) => error.into_inner(),
+            };
+            gate.closed = true;
+            if shutdown_ramp && !dry_run {
+                ramp_down(ec, &gate);
+            }
+            cleanup(ec, dry_run);
+            if let Some(path) = &pid_file {
+                remove_pid_file(path);
             }
+            print_histogram(&histogram, verbose);
+            std::process::exit(0);
         }
+    });
+    return Ok(());
+}
 
-        // If fan speed changed.
-        if fan_speed != fan_speed_last {
-            // Output about the change.
-            log::info!("Changing fan speed {} % => {} %", fan_speed_last, fan_speed);
+// Install handler that asks control loop to reload configuration on SIGHUP.
+fn install_reload_handler() -> Result<std::sync::Arc<std::sync::atomic::AtomicBool>, std::io::Error>
+{
+    let reload: std::sync::Arc<std::sync::atomic::AtomicBool> =
+        std::sync::Arc::new(std::sync::atomic::AtomicBool::new(false));
+    signal_hook::flag::register(signal_hook::consts::SIGHUP, reload.clone())?;
+    return Ok(reload);
+}
 
-            // Zero loop counters.
-            lowering_or_staying_loops = 0;
-            raising_loops = 0;
+// Write log record as JSON object line.
+#[cfg(feature = "json-log")]
+fn format_json(
+    buf: &mut env_logger::fmt::Formatter,
+    record: &log::Record,
+) -> Result<(), std::io::Error> {
+    // Read number from structured log value if record has it.
+    let number = |key: &str| -> serde_json::Value {
+        return match record
+            .key_values()
+            .get(log::kv::Key::from(key))
+            .and_then(|value| value.to_u64())
+        {
+            Some(number) => serde_json::Value::from(number),
+            None => serde_json::Value::Null,
+        };
+    };
+    let line: serde_json::Value = serde_json::json!({
+        "ts": buf.timestamp().to_string(),
+        "level": record.level().to_string(),
+        "temp": number("temp"),
+        "fan_speed": number("fan_speed"),
+        "message": record.args().to_string(),
+    });
+    return std::io::Write::write_fmt(buf, format_args!("{}\n", line));
+}
 
-            // Set fan speed.
-            set_fan_speed(fan_speed)?;
+fn main() {
+    // Parse command line arguments.
+    let cli: Cli = <Cli as clap::Parser>::parse();
 
-            // Save fan speed as last fan speed.
-            fan_speed_last = fan_speed;
-            // Update last loop temperature from this loop temperature.
-            temp_last = temp;
+    // Init logger from command line arguments.
+    let mut logger: env_logger::Builder = env_logger::Builder::new();
+    // Log lines would be drawn over monitor, errors are still printed after it exits.
+    if is_monitor(&cli) {
+        logger.filter_level(log::LevelFilter::Off);
+    } else {
+        logger.filter_level(cli.verbose.log_level_filter());
+    }
+    match cli.log_format {
+        LogFormat::Text => {}
+        #[cfg(feature = "json-log")]
+        LogFormat::Json => {
+            logger.format(format_json);
         }
-        // Fan speed did not change.
-        else {
-            log::info!("Keeping fan speed at {} %", fan_speed);
+    }
+    if let Some(path) = &cli.log_file {
+        match RotatingLogFile::open(path, cli.log_max_bytes) {
+            Ok(log_file) => {
+                logger.target(env_logger::Target::Pipe(Box::new(log_file)));
+            }
+            Err(error) => {
+                eprintln!("Failed to open log file {}: {}", path.display(), error);
+                std::process::exit(1);
+            }
         }
+    }
+    logger.init();
 
-        // Sleep set milli seconds.
-        std::thread::sleep(sleep_time);
+    // Embedded controller access with command line settings and speed register layout from config.
+    let (speed_mask, speed_shift): (u8, u8) = match config_from_cli(&cli, cli.profile) {
+        Ok(config) => match config.validate() {
+            Ok(()) => (config.speed_mask, config.speed_shift),
+            Err(error) => {
+                eprintln!("Got error {}", error);
+                std::process::exit(1);
+            }
+        },
+        Err(error) => {
+            eprintln!("Got error {}", error);
+            std::process::exit(1);
+        }
+    };
+    let ec: Ec = Ec::new(
+        cli.flag_timeout_ms,
+        cli.command_port,
+        cli.data_port,
+        cli.invert_speed,
+        speed_mask,
+        speed_shift,
+        cli.speed_max_raw,
+    );
+
+    // Print effective configuration without touching embedded controller.
+    if cli.dump_config {
+        match load_config(&cli, cli.profile) {
+            Ok(config) => match toml::to_string(&config) {
+                Ok(content) => {
+                    print!("{}", content);
+                    std::process::exit(0);
+                }
+                Err(error) => {
+                    eprintln!("Failed to serialize config {}", error);
+                    std::process::exit(1);
+                }
+            },
+            Err(error) => {
+                eprintln!("Got error {}", error);
+                std::process::exit(1);
+            }
+        }
     }
-}
 
-fn main() {
-    match run() {
-        Ok(()) => std::process::exit(0),
+    // Run subcommand once without taking control of the fans.
+    if let Some(command) = cli.command.as_ref().filter(|_| !is_monitor(&cli)) {
+        match run_command(&cli, command, ec) {
+            Ok(()) => std::process::exit(0),
+            Err(error) => {
+                eprintln!("Got error {}", error);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    // Histogram of temperatures shared with signal handler to print it on shutdown.
+    let histogram: std::sync::Arc<std::sync::Mutex<Histogram>> =
+        std::sync::Arc::new(std::sync::Mutex::new(Histogram::default()));
+    // Fan speeds shared with signal handler to ramp fans down from them and stop writes on shutdown.
+    let gate: std::sync::Arc<std::sync::Mutex<SpeedGate>> =
+        std::sync::Arc::new(std::sync::Mutex::new(SpeedGate::default()));
+    // Any verbose flag over default error level.
+    let verbose: bool = log::LevelFilter::Error < cli.verbose.log_level_filter();
+    if let Err(error) = install_signal_handler(
+        ec,
+        cli.dry_run,
+        cli.pid_file.clone(),
+        histogram.clone(),
+        gate.clone(),
+        cli.shutdown_ramp,
+        verbose,
+    ) {
+        eprintln!("Failed to install signal handler {}", error);
+        std::process::exit(1);
+    }
+    let reload: std::sync::Arc<std::sync::atomic::AtomicBool> = match install_reload_handler() {
+        Ok(reload) => reload,
+        Err(error) => {
+            eprintln!("Failed to install reload handler {}", error);
+            std::process::exit(1);
+        }
+    };
+    // Refuse to fight over the fan with another running controller.
+    if let Some(path) = &cli.pid_file {
+        if let Err(error) = acquire_pid_file(path) {
+            eprintln!("Got error {}", error);
+            std::process::exit(1);
+        }
+    }
+
+    // Fail safe to max speed if control loop panics.
+    install_panic_hook(ec, cli.fans.clone(), cli.dry_run);
+
+    let result: Result<(), RunError> = run(&cli, ec, &reload, &histogram, &gate);
+    if let Some(path) = &cli.pid_file {
+        remove_pid_file(path);
+    }
+    print_histogram(&histogram, verbose);
+    match result {
+        Ok(()) => {
+            // Single iteration leaves applied speed in place.
+            if !cli.once {
+                cleanup(ec, cli.dry_run);
+            }
+            std::process::exit(0)
+        }
         Err(error) => {
             eprintln!("Got error {}", error);
+            cleanup(ec, cli.dry_run);
             std::process::exit(1);
         }
     }
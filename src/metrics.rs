@@ -0,0 +1,87 @@
+use crate::error::FailedToStartMetricsServerError;
+
+// Latest values of a fan exposed as metrics.
+#[derive(Debug, Clone, Default)]
+pub struct FanMetrics {
+    temp: u8,
+    speed: u8,
+    rpm: Option<u16>,
+}
+
+// Latest values of all fans exposed as metrics.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    fans: std::collections::BTreeMap<u8, FanMetrics>,
+}
+impl Metrics {
+    // Update latest values of fan.
+    pub fn update(&mut self, fan_id: u8, temp: u8, speed: u8, rpm: Option<u16>) {
+        self.fans.insert(fan_id, FanMetrics { temp, speed, rpm });
+    }
+
+    // Render metrics in Prometheus text format.
+    pub fn render(&self) -> String {
+        let mut text: String = String::new();
+        text.push_str("# HELP fan_temperature_celsius Temperature of fan in celsius.\n");
+        text.push_str("# TYPE fan_temperature_celsius gauge\n");
+        for (fan_id, fan) in &self.fans {
+            text.push_str(&format!(
+                "fan_temperature_celsius{{fan=\"{}\"}} {}\n",
+                fan_id, fan.temp
+            ));
+        }
+        text.push_str("# HELP fan_speed_percent Speed of fan as percentage.\n");
+        text.push_str("# TYPE fan_speed_percent gauge\n");
+        for (fan_id, fan) in &self.fans {
+            text.push_str(&format!(
+                "fan_speed_percent{{fan=\"{}\"}} {}\n",
+                fan_id, fan.speed
+            ));
+        }
+        text.push_str("# HELP fan_rpm Measured fan RPM.\n");
+        text.push_str("# TYPE fan_rpm gauge\n");
+        for (fan_id, fan) in &self.fans {
+            if let Some(rpm) = fan.rpm {
+                text.push_str(&format!("fan_rpm{{fan=\"{}\"}} {}\n", fan_id, rpm));
+            }
+        }
+        return text;
+    }
+}
+
+// Start serving metrics over HTTP on background thread.
+pub fn start_metrics_server(
+    addr: &str,
+    metrics: std::sync::Arc<std::sync::Mutex<Metrics>>,
+) -> Result<(), FailedToStartMetricsServerError> {
+    let server: tiny_http::Server = match tiny_http::Server::http(addr) {
+        Ok(server) => server,
+        Err(error) => {
+            return Err(FailedToStartMetricsServerError::new(
+                addr.to_string(),
+                error.to_string(),
+            ))
+        }
+    };
+    log::info!("Serving metrics at http://{}/metrics", addr);
+    std::thread::spawn(move || {
+        for request in server.incoming_requests() {
+            let text: String = match metrics.lock() {
+                Ok(metrics) => metrics.render(),
+                Err(error) => error.get_ref().render(),
+            };
+            let mut response: tiny_http::Response<std::io::Cursor<Vec<u8>>> =
+                tiny_http::Response::from_string(text);
+            if let Ok(header) = tiny_http::Header::from_bytes(
+                &b"Content-Type"[..],
+                &b"text/plain; version=0.0.4"[..],
+            ) {
+                response = response.with_header(header);
+            }
+            if let Err(error) = request.respond(response) {
+                log::warn!("Failed to respond to metrics request: {}", error);
+            }
+        }
+    });
+    return Ok(());
+}
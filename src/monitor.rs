@@ -0,0 +1,113 @@
+use crate::error::FailedToDrawMonitorError;
+
+// Latest state of a fan controller drawn by monitor.
+#[derive(Debug, Clone, Default)]
+pub struct MonitorRow {
+    pub temp: u8,
+    pub target: u8,
+    pub fan_speed: u8,
+    pub rpm: Option<u16>,
+    pub raising_loops: u64,
+    pub lowering_or_staying_loops: u64,
+}
+
+// Live terminal dashboard of fan controllers, restores terminal when dropped.
+pub struct Monitor {
+    fans: std::collections::BTreeMap<u8, MonitorRow>,
+}
+impl Monitor {
+    // Take over terminal for drawing.
+    pub fn start() -> Result<Monitor, FailedToDrawMonitorError> {
+        crossterm::terminal::enable_raw_mode().map_err(to_error)?;
+        crossterm::execute!(
+            std::io::stdout(),
+            crossterm::terminal::EnterAlternateScreen,
+            crossterm::cursor::Hide
+        )
+        .map_err(to_error)?;
+        return Ok(Monitor {
+            fans: std::collections::BTreeMap::new(),
+        });
+    }
+
+    // Update latest state of fan.
+    pub fn update(&mut self, fan_id: u8, row: MonitorRow) {
+        self.fans.insert(fan_id, row);
+    }
+
+    // Draw latest state of all fans.
+    pub fn draw(&self) -> Result<(), FailedToDrawMonitorError> {
+        let mut stdout: std::io::Stdout = std::io::stdout();
+        crossterm::queue!(
+            stdout,
+            crossterm::terminal::Clear(crossterm::terminal::ClearType::All),
+            crossterm::cursor::MoveTo(0, 0),
+            crossterm::style::Print(format!(
+                "{:>4} {:>6} {:>7} {:>6} {:>6} {:>8} {:>8}",
+                "Fan", "Temp", "Target", "Speed", "RPM", "Raising", "Lowering"
+            )),
+        )
+        .map_err(to_error)?;
+        for (line, (fan_id, fan)) in self.fans.iter().enumerate() {
+            let rpm: String = match fan.rpm {
+                Some(rpm) => rpm.to_string(),
+                None => "-".to_string(),
+            };
+            crossterm::queue!(
+                stdout,
+                crossterm::cursor::MoveTo(0, line as u16 + 1),
+                crossterm::style::Print(format!(
+                    "{:>4} {:>4} C {:>5} % {:>4} % {:>6} {:>8} {:>8}",
+                    fan_id,
+                    fan.temp,
+                    fan.target,
+                    fan.fan_speed,
+                    rpm,
+                    fan.raising_loops,
+                    fan.lowering_or_staying_loops
+                )),
+            )
+            .map_err(to_error)?;
+        }
+        crossterm::queue!(
+            stdout,
+            crossterm::cursor::MoveTo(0, self.fans.len() as u16 + 2),
+            crossterm::style::Print("Press q to quit and restore automatic fan control."),
+        )
+        .map_err(to_error)?;
+        std::io::Write::flush(&mut stdout).map_err(to_error)?;
+        return Ok(());
+    }
+
+    // Has q or Ctrl-C been pressed since last check, raw mode keeps Ctrl-C from sending SIGINT.
+    pub fn quit_requested(&self) -> Result<bool, FailedToDrawMonitorError> {
+        while crossterm::event::poll(std::time::Duration::ZERO).map_err(to_error)? {
+            if let crossterm::event::Event::Key(key) = crossterm::event::read().map_err(to_error)? {
+                let ctrl_c: bool = key.code == crossterm::event::KeyCode::Char('c')
+                    && key
+                        .modifiers
+                        .contains(crossterm::event::KeyModifiers::CONTROL);
+                if key.code == crossterm::event::KeyCode::Char('q') || ctrl_c {
+                    return Ok(true);
+                }
+            }
+        }
+        return Ok(false);
+    }
+}
+impl Drop for Monitor {
+    fn drop(&mut self) {
+        // Nothing left to report errors to while restoring terminal.
+        let _ = crossterm::execute!(
+            std::io::stdout(),
+            crossterm::cursor::Show,
+            crossterm::terminal::LeaveAlternateScreen
+        );
+        let _ = crossterm::terminal::disable_raw_mode();
+    }
+}
+
+// Convert terminal IO error to monitor error.
+fn to_error(error: std::io::Error) -> FailedToDrawMonitorError {
+    return FailedToDrawMonitorError::new(error.to_string());
+}
@@ -0,0 +1,73 @@
+// Default port of MQTT broker.
+pub const MQTT_PORT_DEFAULT: u16 = 1883;
+// How many messages can wait to be sent to broker before new ones are dropped.
+pub const MQTT_QUEUE_CAPACITY: usize = 16;
+
+// Publishes temperatures and fan speeds to MQTT broker.
+pub struct MqttPublisher {
+    client: rumqttc::Client,
+    prefix: String,
+    // Are multiple fans published, so that topics need fan ids.
+    multiple_fans: bool,
+}
+impl MqttPublisher {
+    // Connect to broker given as mqtt://host:port, connection is kept up on background thread.
+    pub fn connect(broker: &str, prefix: &str, multiple_fans: bool) -> MqttPublisher {
+        let address: &str = broker.strip_prefix("mqtt://").unwrap_or(broker);
+        let (host, port): (&str, u16) = match address.rsplit_once(':') {
+            Some((host, port)) => match port.parse::<u16>() {
+                Ok(port) => (host, port),
+                Err(_) => (address, MQTT_PORT_DEFAULT),
+            },
+            None => (address, MQTT_PORT_DEFAULT),
+        };
+        let options: rumqttc::MqttOptions = rumqttc::MqttOptions::new(
+            format!("ns50mu-fan-controller-{}", std::process::id()),
+            host,
+            port,
+        );
+        let (client, mut connection): (rumqttc::Client, rumqttc::Connection) =
+            rumqttc::Client::new(options, MQTT_QUEUE_CAPACITY);
+
+        // Connection reconnects when iterated after error, so keep iterating and only log errors.
+        std::thread::spawn(move || {
+            for notification in connection.iter() {
+                if let Err(error) = notification {
+                    log::warn!("MQTT connection error, retrying: {}", error);
+                    std::thread::sleep(std::time::Duration::from_secs(1));
+                }
+            }
+        });
+        log::info!(
+            "Publishing to MQTT broker {}:{} under {}",
+            host,
+            port,
+            prefix
+        );
+        return MqttPublisher {
+            client,
+            prefix: prefix.trim_end_matches('/').to_string(),
+            multiple_fans,
+        };
+    }
+
+    // Publish temperature and fan speed of fan without blocking,
+    // to <prefix>/temp or with multiple fans to <prefix>/<fan id>/temp.
+    pub fn publish(&mut self, fan_id: u8, temp: u8, speed: u8) {
+        let topic: String = if self.multiple_fans {
+            format!("{}/{}", self.prefix, fan_id)
+        } else {
+            self.prefix.clone()
+        };
+        for (name, value) in [("temp", temp), ("speed", speed)] {
+            if let Err(error) = self.client.try_publish(
+                format!("{}/{}", topic, name),
+                rumqttc::QoS::AtMostOnce,
+                false,
+                value.to_string(),
+            ) {
+                log::debug!("Failed to publish to MQTT: {}", error);
+            }
+        }
+    }
+}
@@ -0,0 +1,34 @@
+use crate::error::PidFileError;
+
+// Is process with given id still running?
+fn process_alive(pid: i32) -> bool {
+    if unsafe { libc::kill(pid, 0) } == 0 {
+        return true;
+    }
+    // Process exists but belongs to someone else.
+    let errno: i32 = unsafe { *libc::__errno_location() };
+    return errno == libc::EPERM;
+}
+
+// Write id of this process to given file, failing if process in existing file is still running.
+pub fn acquire_pid_file(path: &std::path::Path) -> Result<(), PidFileError> {
+    // File left over from crashed run can be replaced.
+    if let Ok(content) = std::fs::read_to_string(path) {
+        if let Ok(pid) = content.trim().parse::<i32>() {
+            if 0 < pid && pid != std::process::id() as i32 && process_alive(pid) {
+                return Err(PidFileError::AlreadyRunningError(path.to_path_buf(), pid));
+            }
+        }
+    }
+    if let Err(error) = std::fs::write(path, format!("{}\n", std::process::id())) {
+        return Err(PidFileError::WriteError(path.to_path_buf(), error));
+    }
+    return Ok(());
+}
+
+// Remove PID file and output error if it fails.
+pub fn remove_pid_file(path: &std::path::Path) {
+    if let Err(error) = std::fs::remove_file(path) {
+        eprintln!("Failed to remove PID file {}: {}", path.display(), error);
+    }
+}
@@ -0,0 +1,88 @@
+// Directory of power supplies in sysfs.
+pub const POWER_SUPPLY_PATH: &str = "/sys/class/power_supply";
+// How many milliseconds to wait between power supply checks.
+pub const POWER_CHECK_MS: u64 = 2000;
+// How many milliseconds power state must stay same before switching to it.
+pub const POWER_DEBOUNCE_MS: u64 = 10000;
+
+// State of power state shared with monitor thread.
+const POWER_UNKNOWN: u8 = 0;
+const POWER_BATTERY: u8 = 1;
+const POWER_AC: u8 = 2;
+
+// Is AC adapter online, none if there is no AC adapter to read.
+pub fn ac_online() -> Option<bool> {
+    let entries: std::fs::ReadDir = std::fs::read_dir(POWER_SUPPLY_PATH).ok()?;
+    let mut found: bool = false;
+    for entry in entries.flatten() {
+        if !entry.file_name().to_string_lossy().starts_with("AC") {
+            continue;
+        }
+        if let Ok(content) = std::fs::read_to_string(entry.path().join("online")) {
+            found = true;
+            // Any online adapter powers the machine.
+            if content.trim() == "1" {
+                return Some(true);
+            }
+        }
+    }
+    return if found { Some(false) } else { None };
+}
+
+// Watches power state on background thread and debounces changes to it.
+#[derive(Debug)]
+pub struct PowerMonitor {
+    // Latest power state read by monitor thread.
+    state: std::sync::Arc<std::sync::atomic::AtomicU8>,
+    // Power state that was last switched to.
+    applied: Option<bool>,
+    // Since when power state has differed from applied one.
+    pending_since: Option<std::time::Instant>,
+}
+impl PowerMonitor {
+    // Start checking power state on background thread.
+    pub fn start() -> PowerMonitor {
+        let state: std::sync::Arc<std::sync::atomic::AtomicU8> =
+            std::sync::Arc::new(std::sync::atomic::AtomicU8::new(POWER_UNKNOWN));
+        let thread_state: std::sync::Arc<std::sync::atomic::AtomicU8> = state.clone();
+        std::thread::spawn(move || loop {
+            let value: u8 = match ac_online() {
+                Some(true) => POWER_AC,
+                Some(false) => POWER_BATTERY,
+                None => POWER_UNKNOWN,
+            };
+            thread_state.store(value, std::sync::atomic::Ordering::SeqCst);
+            std::thread::sleep(std::time::Duration::from_millis(POWER_CHECK_MS));
+        });
+        return PowerMonitor {
+            state,
+            applied: None,
+            pending_since: None,
+        };
+    }
+
+    // Returns whether running on AC when power state should be switched,
+    // first known state is switched to right away and later ones once they have stayed long enough.
+    pub fn poll(&mut self) -> Option<bool> {
+        let on_ac: bool = match self.state.load(std::sync::atomic::Ordering::SeqCst) {
+            POWER_AC => true,
+            POWER_BATTERY => false,
+            _ => return None,
+        };
+        if self.applied == Some(on_ac) {
+            self.pending_since = None;
+            return None;
+        }
+        if self.applied.is_some() {
+            let since: std::time::Instant = *self
+                .pending_since
+                .get_or_insert_with(std::time::Instant::now);
+            if since.elapsed() < std::time::Duration::from_millis(POWER_DEBOUNCE_MS) {
+                return None;
+            }
+        }
+        self.applied = Some(on_ac);
+        self.pending_since = None;
+        return Some(on_ac);
+    }
+}
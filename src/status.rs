@@ -0,0 +1,102 @@
+use crate::error::FailedToStartStatusSocketError;
+
+// Latest state of a fan controller exposed through status socket.
+#[derive(Debug, Clone, Default)]
+pub struct FanStatus {
+    pub temp: u8,
+    pub fan_speed: u8,
+    pub raising_loops: u64,
+    pub lowering_or_staying_loops: u64,
+}
+
+// Latest state of all fan controllers exposed through status socket.
+#[derive(Debug)]
+pub struct Status {
+    // When controller was started.
+    started: std::time::Instant,
+    fans: std::collections::BTreeMap<u8, FanStatus>,
+}
+impl Status {
+    pub fn new() -> Status {
+        Status {
+            started: std::time::Instant::now(),
+            fans: std::collections::BTreeMap::new(),
+        }
+    }
+
+    // Update latest state of fan.
+    pub fn update(&mut self, fan_id: u8, status: FanStatus) {
+        self.fans.insert(fan_id, status);
+    }
+
+    // Render status as JSON object.
+    pub fn render(&self) -> String {
+        let fans: Vec<String> = self
+            .fans
+            .iter()
+            .map(|(fan_id, fan)| {
+                format!(
+                    "{{\"fan\":{},\"temp\":{},\"fan_speed\":{},\"raising_loops\":{},\"lowering_or_staying_loops\":{}}}",
+                    fan_id, fan.temp, fan.fan_speed, fan.raising_loops, fan.lowering_or_staying_loops
+                )
+            })
+            .collect();
+        return format!(
+            "{{\"uptime_seconds\":{},\"fans\":[{}]}}\n",
+            self.started.elapsed().as_secs(),
+            fans.join(",")
+        );
+    }
+}
+impl Default for Status {
+    fn default() -> Status {
+        Status::new()
+    }
+}
+
+// Start writing status snapshot to every client connecting to Unix socket on background thread.
+pub fn start_status_socket(
+    path: &std::path::Path,
+    status: std::sync::Arc<std::sync::Mutex<Status>>,
+) -> Result<(), FailedToStartStatusSocketError> {
+    // Remove socket left over from earlier run.
+    if path.exists() {
+        if let Err(error) = std::fs::remove_file(path) {
+            return Err(FailedToStartStatusSocketError::new(
+                path.to_path_buf(),
+                error.to_string(),
+            ));
+        }
+    }
+    let listener: std::os::unix::net::UnixListener =
+        match std::os::unix::net::UnixListener::bind(path) {
+            Ok(listener) => listener,
+            Err(error) => {
+                return Err(FailedToStartStatusSocketError::new(
+                    path.to_path_buf(),
+                    error.to_string(),
+                ))
+            }
+        };
+    log::info!("Serving status at {}", path.display());
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream: std::os::unix::net::UnixStream = match stream {
+                Ok(stream) => stream,
+                Err(error) => {
+                    log::warn!("Failed to accept status connection: {}", error);
+                    continue;
+                }
+            };
+            let text: String = match status.lock() {
+                Ok(status) => status.render(),
+                Err(error) => error.get_ref().render(),
+            };
+            // Stream is closed when dropped.
+            if let Err(error) = std::io::Write::write_all(&mut stream, text.as_bytes()) {
+                log::warn!("Failed to write status: {}", error);
+            }
+        }
+    });
+    return Ok(());
+}
@@ -0,0 +1,419 @@
+use crate::ec::Ec;
+use crate::error::{
+    FailedToReadSysfsTemperatureError, InvalidTemperatureReadingError, TemperatureReadError,
+};
+
+// Default path for hwmon temperature input in millidegrees.
+pub const HWMON_PATH_DEFAULT: &str = "/sys/class/hwmon/hwmon0/temp1_input";
+
+// Directory of hwmon devices in sysfs.
+pub const HWMON_CLASS_PATH: &str = "/sys/class/hwmon";
+
+// Hwmon temperature input found in sysfs.
+#[derive(Debug, Clone)]
+pub struct HwmonInput {
+    // Path to temperature input, usable as --hwmon-path.
+    pub path: std::path::PathBuf,
+    // Name of hwmon device, for example coretemp.
+    pub name: String,
+    // Label of temperature input if device has one.
+    pub label: Option<String>,
+    // Current temperature in celsius if it could be read.
+    pub temp: Option<f32>,
+}
+
+// List temperature inputs of all hwmon devices, sorted by path.
+pub fn list_hwmon_inputs() -> Vec<HwmonInput> {
+    let mut inputs: Vec<HwmonInput> = Vec::new();
+    let devices: std::fs::ReadDir = match std::fs::read_dir(HWMON_CLASS_PATH) {
+        Ok(devices) => devices,
+        Err(_) => return inputs,
+    };
+    for device in devices.flatten() {
+        let device_path: std::path::PathBuf = device.path();
+        let name: String = std::fs::read_to_string(device_path.join("name"))
+            .map(|name| name.trim().to_string())
+            .unwrap_or_default();
+        let files: std::fs::ReadDir = match std::fs::read_dir(&device_path) {
+            Ok(files) => files,
+            Err(_) => continue,
+        };
+        for file in files.flatten() {
+            let file_name: String = file.file_name().to_string_lossy().to_string();
+            let prefix: &str = match file_name.strip_suffix("_input") {
+                Some(prefix) if prefix.starts_with("temp") => prefix,
+                _ => continue,
+            };
+            inputs.push(HwmonInput {
+                path: file.path(),
+                name: name.clone(),
+                label: std::fs::read_to_string(device_path.join(format!("{}_label", prefix)))
+                    .ok()
+                    .map(|label| label.trim().to_string()),
+                temp: std::fs::read_to_string(file.path())
+                    .ok()
+                    .and_then(|content| content.trim().parse::<i64>().ok())
+                    .map(|millidegrees| millidegrees as f32 / 1000_f32),
+            });
+        }
+    }
+    inputs.sort_by(|a, b| a.path.cmp(&b.path));
+    return inputs;
+}
+
+// Convert whole degrees to tenths of degree used for higher resolution temperatures.
+pub fn celsius_to_tenths(celsius: u8) -> u16 {
+    return celsius as u16 * 10;
+}
+
+// Convert tenths of degree to nearest whole degrees.
+pub fn tenths_to_celsius(tenths: u16) -> u8 {
+    return std::cmp::min(tenths.saturating_add(5) / 10, u8::MAX as u16) as u8;
+}
+
+// Format temperature in tenths of degree for output with one decimal place,
+// converting it to fahrenheit if asked.
+pub fn format_tenths(tenths: u16, fahrenheit: bool) -> String {
+    let celsius: f32 = tenths as f32 / 10_f32;
+    if fahrenheit {
+        return format!("{:.1} °F", celsius * 1.8 + 32_f32);
+    }
+    return format!("{:.1} C", celsius);
+}
+
+// Format celsius temperature for output, converting it to fahrenheit if asked.
+pub fn format_temp(celsius: u8, fahrenheit: bool) -> String {
+    if fahrenheit {
+        return format!("{:.1} °F", celsius as f32 * 1.8 + 32_f32);
+    }
+    return format!("{} C", celsius);
+}
+
+// Source of temperature readings.
+pub trait TemperatureSource {
+    // Read temperature as celsius.
+    fn read(&mut self) -> Result<u8, TemperatureReadError>;
+
+    // Read temperature in tenths of degree,
+    // sources without higher resolution give whole degrees.
+    fn read_tenths(&mut self) -> Result<u16, TemperatureReadError> {
+        return Ok(celsius_to_tenths(self.read()?));
+    }
+}
+
+// Temperature of fan read from embedded controller.
+#[derive(Debug)]
+pub struct EcTemperature {
+    ec: Ec,
+    // Index of embedded controller temperature sensor.
+    sensor_id: u8,
+    // Reading that means embedded controller could not give temperature.
+    invalid: u8,
+}
+impl EcTemperature {
+    pub fn new(ec: Ec, sensor_id: u8, invalid: u8) -> EcTemperature {
+        EcTemperature {
+            ec,
+            sensor_id,
+            invalid,
+        }
+    }
+}
+impl TemperatureSource for EcTemperature {
+    fn read(&mut self) -> Result<u8, TemperatureReadError> {
+        let temp: u8 = self.ec.get_temp(self.sensor_id)?;
+        if temp == self.invalid {
+            return Err(InvalidTemperatureReadingError::new(self.sensor_id, temp).into());
+        }
+        return Ok(temp);
+    }
+
+    // Read second byte as fraction of degree.
+    fn read_tenths(&mut self) -> Result<u16, TemperatureReadError> {
+        let (whole, fraction): (u8, u8) = self.ec.get_temp_fine(self.sensor_id)?;
+        if whole == self.invalid {
+            return Err(InvalidTemperatureReadingError::new(self.sensor_id, whole).into());
+        }
+        return Ok(celsius_to_tenths(whole) + (fraction as u16 * 10 + 128) / 256);
+    }
+}
+
+// Fixed temperature for dry runs.
+#[derive(Debug)]
+pub struct FakeTemperature {
+    temp: u8,
+}
+impl FakeTemperature {
+    pub fn new(temp: u8) -> FakeTemperature {
+        FakeTemperature { temp }
+    }
+}
+impl TemperatureSource for FakeTemperature {
+    fn read(&mut self) -> Result<u8, TemperatureReadError> {
+        return Ok(self.temp);
+    }
+}
+
+// Scripted temperatures for tests, read in order and repeating last one after script ends.
+#[cfg(test)]
+#[derive(Debug)]
+pub struct MockTemperature {
+    temps: Vec<u8>,
+    // Index of next temperature to read.
+    next: usize,
+}
+#[cfg(test)]
+impl MockTemperature {
+    pub fn new(temps: Vec<u8>) -> MockTemperature {
+        MockTemperature { temps, next: 0 }
+    }
+}
+#[cfg(test)]
+impl TemperatureSource for MockTemperature {
+    fn read(&mut self) -> Result<u8, TemperatureReadError> {
+        let index: usize = std::cmp::min(self.next, self.temps.len().saturating_sub(1));
+        self.next = self.next.saturating_add(1);
+        return Ok(self.temps[index]);
+    }
+}
+
+// Temperature read from sysfs hwmon input.
+#[derive(Debug)]
+pub struct SysfsTemperature {
+    path: std::path::PathBuf,
+}
+impl SysfsTemperature {
+    pub fn new(path: std::path::PathBuf) -> SysfsTemperature {
+        SysfsTemperature { path }
+    }
+
+    // Read temperature input as millidegrees.
+    fn read_millidegrees(&self) -> Result<i64, TemperatureReadError> {
+        let content: String = match std::fs::read_to_string(&self.path) {
+            Ok(content) => content,
+            Err(error) => {
+                return Err(TemperatureReadError::FailedToReadSysfsTemperatureError(
+                    FailedToReadSysfsTemperatureError::new(self.path.clone(), error.to_string()),
+                ))
+            }
+        };
+        let millidegrees: i64 = match content.trim().parse::<i64>() {
+            Ok(millidegrees) => millidegrees,
+            Err(error) => {
+                return Err(TemperatureReadError::FailedToReadSysfsTemperatureError(
+                    FailedToReadSysfsTemperatureError::new(self.path.clone(), error.to_string()),
+                ))
+            }
+        };
+        return Ok(millidegrees);
+    }
+}
+impl TemperatureSource for SysfsTemperature {
+    fn read(&mut self) -> Result<u8, TemperatureReadError> {
+        // Convert millidegrees to whole degrees that fit into u8.
+        return Ok((self.read_millidegrees()? / 1000).clamp(0, u8::MAX as i64) as u8);
+    }
+
+    fn read_tenths(&mut self) -> Result<u16, TemperatureReadError> {
+        return Ok(
+            (self.read_millidegrees()? / 100).clamp(0, celsius_to_tenths(u8::MAX) as i64) as u16,
+        );
+    }
+}
+
+// Directory of thermal zones in sysfs.
+pub const THERMAL_ZONE_CLASS_PATH: &str = "/sys/class/thermal";
+// Type of thermal zone that measures CPU package temperature.
+pub const THERMAL_ZONE_TYPE_CPU: &str = "x86_pkg_temp";
+
+// Path to temperature of thermal zone with given number.
+pub fn thermal_zone_path(zone: u32) -> std::path::PathBuf {
+    return std::path::Path::new(THERMAL_ZONE_CLASS_PATH)
+        .join(format!("thermal_zone{}", zone))
+        .join("temp");
+}
+
+// Find number of first thermal zone with given type.
+pub fn find_thermal_zone(zone_type: &str) -> Option<u32> {
+    let mut zones: Vec<u32> = std::fs::read_dir(THERMAL_ZONE_CLASS_PATH)
+        .ok()?
+        .flatten()
+        .filter_map(|entry| {
+            entry
+                .file_name()
+                .to_string_lossy()
+                .strip_prefix("thermal_zone")
+                .and_then(|zone| zone.parse::<u32>().ok())
+        })
+        .collect();
+    zones.sort();
+    return zones.into_iter().find(|zone| {
+        std::fs::read_to_string(
+            std::path::Path::new(THERMAL_ZONE_CLASS_PATH)
+                .join(format!("thermal_zone{}", zone))
+                .join("type"),
+        )
+        .map(|content| content.trim() == zone_type)
+        .unwrap_or(false)
+    });
+}
+
+// Temperature read from thermal zone in millidegrees, same format as hwmon input.
+#[derive(Debug)]
+pub struct ThermalZoneTemperature {
+    sysfs: SysfsTemperature,
+}
+impl ThermalZoneTemperature {
+    pub fn new(zone: u32) -> ThermalZoneTemperature {
+        ThermalZoneTemperature {
+            sysfs: SysfsTemperature::new(thermal_zone_path(zone)),
+        }
+    }
+}
+impl TemperatureSource for ThermalZoneTemperature {
+    fn read(&mut self) -> Result<u8, TemperatureReadError> {
+        return self.sysfs.read();
+    }
+
+    fn read_tenths(&mut self) -> Result<u16, TemperatureReadError> {
+        return self.sysfs.read_tenths();
+    }
+}
+
+// Strategy for combining temperatures of multiple sources.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "bin", derive(clap::ValueEnum))]
+pub enum Aggregate {
+    // Hottest temperature.
+    Max,
+    // Mean of temperatures.
+    Mean,
+    // Mean of temperatures weighted by source weights.
+    Weighted,
+}
+
+// Combines temperatures of multiple sources into single temperature.
+pub struct Aggregator {
+    // Sources with their weights.
+    sources: Vec<(Box<dyn TemperatureSource>, f32)>,
+    strategy: Aggregate,
+}
+impl Aggregator {
+    pub fn new(strategy: Aggregate) -> Aggregator {
+        Aggregator {
+            sources: Vec::new(),
+            strategy,
+        }
+    }
+
+    // Add source with weight used by weighted strategy.
+    pub fn add(&mut self, source: Box<dyn TemperatureSource>, weight: f32) {
+        self.sources.push((source, weight));
+    }
+
+    // Combine temperatures with their weights by strategy.
+    fn combine(&self, temps: &[(f32, f32)]) -> f32 {
+        let max: f32 = temps.iter().map(|(temp, _)| *temp).fold(0.0, f32::max);
+        return match self.strategy {
+            Aggregate::Max => max,
+            Aggregate::Mean => {
+                let sum: f32 = temps.iter().map(|(temp, _)| *temp).sum();
+                sum / temps.len() as f32
+            }
+            Aggregate::Weighted => {
+                let weight_sum: f32 = temps.iter().map(|(_, weight)| *weight).sum();
+                // Without any weight fall back to hottest temperature to be safe.
+                if weight_sum <= 0.0 {
+                    max
+                } else {
+                    let sum: f32 = temps.iter().map(|(temp, weight)| *temp * *weight).sum();
+                    sum / weight_sum
+                }
+            }
+        };
+    }
+}
+impl TemperatureSource for Aggregator {
+    // Read all sources, failing if any of them fails so controller can run fan safely.
+    fn read(&mut self) -> Result<u8, TemperatureReadError> {
+        let mut temps: Vec<(f32, f32)> = Vec::with_capacity(self.sources.len());
+        for (source, weight) in self.sources.iter_mut() {
+            temps.push((source.read()? as f32, *weight));
+        }
+        if temps.is_empty() {
+            return Ok(0);
+        }
+        return Ok(self.combine(&temps).round() as u8);
+    }
+
+    fn read_tenths(&mut self) -> Result<u16, TemperatureReadError> {
+        let mut temps: Vec<(f32, f32)> = Vec::with_capacity(self.sources.len());
+        for (source, weight) in self.sources.iter_mut() {
+            temps.push((source.read_tenths()? as f32, *weight));
+        }
+        if temps.is_empty() {
+            return Ok(0);
+        }
+        return Ok(self.combine(&temps).round() as u16);
+    }
+}
+
+// Smooths temperatures with rolling mean of last samples.
+#[derive(Debug)]
+pub struct TempSmoother {
+    window: std::collections::VecDeque<u16>,
+    size: usize,
+}
+impl TempSmoother {
+    pub fn new(size: usize) -> TempSmoother {
+        TempSmoother {
+            window: std::collections::VecDeque::with_capacity(size),
+            size,
+        }
+    }
+
+    // Add temperature sample and return rounded mean of samples in window.
+    pub fn push(&mut self, temp: u16) -> u16 {
+        if self.size <= self.window.len() {
+            self.window.pop_front();
+        }
+        self.window.push_back(temp);
+        return self.mean().unwrap_or(temp);
+    }
+
+    // Rounded mean of samples in window, none before first sample.
+    pub fn mean(&self) -> Option<u16> {
+        // Average only samples seen so far until window fills.
+        let count: u32 = self.window.len() as u32;
+        if count == 0 {
+            return None;
+        }
+        let sum: u32 = self.window.iter().map(|temp| *temp as u32).sum();
+        return Some(((sum + count / 2) / count) as u16);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Mock gives scripted temperatures in order and then holds last one.
+    #[test]
+    fn mock_temperature_plays_script() {
+        let mut source: MockTemperature = MockTemperature::new(vec![40, 60, 90]);
+        let temps: Vec<u8> = (0..5).map(|_| source.read().unwrap()).collect();
+        assert_eq!(temps, vec![40, 60, 90, 90, 90]);
+        assert_eq!(source.read_tenths().unwrap(), 900);
+    }
+
+    // Smoother averages only samples seen so far until window fills, then drops oldest.
+    #[test]
+    fn smoother_averages_samples_seen() {
+        let mut smoother: TempSmoother = TempSmoother::new(3);
+        assert_eq!(smoother.mean(), None);
+        assert_eq!(smoother.push(600), 600);
+        assert_eq!(smoother.push(700), 650);
+        assert_eq!(smoother.push(800), 700);
+        assert_eq!(smoother.push(900), 800);
+    }
+}
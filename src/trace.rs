@@ -0,0 +1,74 @@
+use crate::error::FailedToReadTraceError;
+
+// Recorded temperature at given time.
+#[derive(Debug, Clone, Copy)]
+pub struct TraceSample {
+    // Seconds since start of trace or since epoch.
+    pub timestamp: f64,
+    // Temperature in celsius.
+    pub temp: u8,
+}
+
+// Read temperature trace from CSV file with timestamp and temp columns.
+// Header is optional, without it first column is timestamp and second is temperature.
+// Files written with --log-csv also work, rows of other fans than given one are skipped.
+pub fn read_trace(
+    path: &std::path::Path,
+    fan_id: u8,
+) -> Result<Vec<TraceSample>, FailedToReadTraceError> {
+    let content: String = std::fs::read_to_string(path)
+        .map_err(|error| FailedToReadTraceError::new(path.to_path_buf(), error.to_string()))?;
+
+    let mut timestamp_column: usize = 0;
+    let mut temp_column: usize = 1;
+    let mut fan_column: Option<usize> = None;
+    let mut samples: Vec<TraceSample> = Vec::new();
+    for (index, line) in content.lines().enumerate() {
+        let line: &str = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let columns: Vec<&str> = line.split(',').map(|column| column.trim()).collect();
+
+        // Header line tells which columns to read.
+        if index == 0 && columns.iter().any(|column| column.parse::<f64>().is_err()) {
+            for (column_index, column) in columns.iter().enumerate() {
+                match *column {
+                    "timestamp" => timestamp_column = column_index,
+                    "temp" => temp_column = column_index,
+                    "fan" => fan_column = Some(column_index),
+                    _ => {}
+                }
+            }
+            continue;
+        }
+
+        let column = |column_index: usize| -> Result<&str, FailedToReadTraceError> {
+            return columns.get(column_index).copied().ok_or_else(|| {
+                FailedToReadTraceError::new(
+                    path.to_path_buf(),
+                    format!("line {} has no column {}", index + 1, column_index + 1),
+                )
+            });
+        };
+        if let Some(fan_column) = fan_column {
+            if column(fan_column)?.parse::<u8>().ok() != Some(fan_id) {
+                continue;
+            }
+        }
+        let timestamp: f64 = column(timestamp_column)?.parse::<f64>().map_err(|error| {
+            FailedToReadTraceError::new(
+                path.to_path_buf(),
+                format!("line {} has invalid timestamp: {}", index + 1, error),
+            )
+        })?;
+        let temp: u8 = column(temp_column)?.parse::<u8>().map_err(|error| {
+            FailedToReadTraceError::new(
+                path.to_path_buf(),
+                format!("line {} has invalid temperature: {}", index + 1, error),
+            )
+        })?;
+        samples.push(TraceSample { timestamp, temp });
+    }
+    return Ok(samples);
+}